@@ -0,0 +1,146 @@
+//! Measures what the SMT node cache does for block application and coin proofs.
+//!
+//! Run with `cargo bench --bench forest_cache`.
+
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use melnode::storage::{Storage, StorageConfig};
+use melstf::GenesisConfig;
+use melstructs::{
+    BlockHeight, CoinData, CoinID, CoinValue, ConsensusProof, Denom, NetID, StakeDoc, Transaction,
+    TxHash, TxKind,
+};
+use melvm::Covenant;
+use stdcode::StdcodeSerializeExt;
+use tmelcrypt::{Ed25519SK, HashVal};
+
+/// Blocks in the synthetic chain.
+const BLOCKS: usize = 300;
+/// Coins created by each block's one transaction.
+const OUTPUTS: usize = 50;
+/// Coin proofs served against the final state.
+const PROOFS: usize = 20_000;
+
+fn coin(value: u128) -> CoinData {
+    CoinData {
+        covhash: Covenant::always_true().hash(),
+        value: CoinValue(value),
+        denom: Denom::Mel,
+        additional_data: Default::default(),
+    }
+}
+
+/// Spends a coin into one change coin and many small ones.
+fn split(input: CoinID, value: u128) -> Transaction {
+    let mut outputs = vec![coin(value - OUTPUTS as u128 + 1)];
+    outputs.extend((1..OUTPUTS).map(|_| coin(1)));
+    Transaction {
+        kind: TxKind::Normal,
+        inputs: vec![input],
+        outputs,
+        fee: CoinValue(0),
+        covenants: vec![Covenant::always_true().to_bytes()],
+        data: Default::default(),
+        sigs: vec![],
+    }
+}
+
+/// Builds the chain, then serves random coin proofs, timing each half.
+async fn run(cache_bytes: u64) -> (Duration, Duration) {
+    let dir = std::env::temp_dir().join(format!(
+        "melnode-bench-forest-cache-{}-{}",
+        cache_bytes,
+        std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    let signer = Ed25519SK::generate();
+    let mut stakes = BTreeMap::new();
+    stakes.insert(
+        TxHash(HashVal::random()),
+        StakeDoc {
+            pubkey: signer.to_public(),
+            e_start: 0,
+            e_post_end: 1_000_000,
+            syms_staked: CoinValue(1_000_000),
+        },
+    );
+    let genesis = GenesisConfig {
+        network: NetID::Testnet,
+        init_coindata: coin(1 << 60),
+        stakes,
+        init_fee_pool: CoinValue(0),
+        init_fee_multiplier: 0,
+    };
+    let storage = Storage::open(
+        dir.clone(),
+        genesis,
+        StorageConfig {
+            forest_cache_bytes: cache_bytes,
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+
+    let start = Instant::now();
+    let mut change = (CoinID::zero_zero(), 1u128 << 60);
+    let mut coins = vec![];
+    for _ in 0..BLOCKS {
+        let tx = split(change.0, change.1);
+        let mut next = storage.highest_state().await.unwrap().next_unsealed();
+        next.apply_tx(&tx).unwrap();
+        let block = next.seal(None).to_block();
+        let mut proof = ConsensusProof::new();
+        proof.insert(
+            signer.to_public(),
+            signer.sign(&block.header.hash().0).into(),
+        );
+        storage.apply_block(block, proof).await.unwrap();
+        coins.extend((1..OUTPUTS).map(|i| CoinID::new(tx.hash_nosigs(), i as u8)));
+        change = (CoinID::new(tx.hash_nosigs(), 0), tx.outputs[0].value.0);
+    }
+    let apply = start.elapsed();
+
+    let height = storage.highest_height().await.unwrap();
+    // only the SMT walk is timed, since fetching the state is the same with or without the cache
+    let coins_smt = storage.get_state(height).await.unwrap().raw_coins_smt();
+    let start = Instant::now();
+    for _ in 0..PROOFS {
+        let id = coins[fastrand::usize(..coins.len())];
+        let (val, _) = coins_smt.get_with_proof(tmelcrypt::hash_single(id.stdcode()).0);
+        assert!(!val.is_empty());
+    }
+    let prove = start.elapsed();
+
+    if let Some(stats) = storage.forest().storage().cache_stats() {
+        println!("  cache: {:?}", stats);
+    }
+    drop(storage);
+    let _ = std::fs::remove_dir_all(&dir);
+    assert_eq!(height, BlockHeight(BLOCKS as u64));
+    (apply, prove)
+}
+
+fn main() {
+    smolscale::block_on(async {
+        for (label, cache_bytes) in [("uncached", 0), ("256 MiB cache", 256 << 20)] {
+            println!("{}:", label);
+            let (apply, prove) = run(cache_bytes).await;
+            println!(
+                "  applied {} blocks in {:.2}ms ({:.3}ms per block)",
+                BLOCKS,
+                apply.as_secs_f64() * 1000.0,
+                apply.as_secs_f64() * 1000.0 / BLOCKS as f64
+            );
+            println!(
+                "  served {} coin proofs in {:.2}ms ({:.2}us per proof)",
+                PROOFS,
+                prove.as_secs_f64() * 1000.0,
+                prove.as_secs_f64() * 1e6 / PROOFS as f64
+            );
+        }
+    })
+}
@@ -1,13 +1,13 @@
-use crate::storage::Storage;
+use crate::storage::{ForestBackend, ReadStorage, Storage, StorageConfig};
 
-use std::{net::SocketAddr, path::PathBuf};
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
 
 use anyhow::Context;
-use clap::Parser;
+use clap::{Args, Parser};
 use serde::{Deserialize, Serialize};
 
 use melstf::GenesisConfig;
-use melstructs::Address;
+use melstructs::{Address, Checkpoint};
 use tap::Tap;
 use tmelcrypt::Ed25519SK;
 
@@ -31,22 +31,13 @@ pub struct MainArgs {
     #[arg(long, default_value = "auto")]
     bootstrap: Vec<String>,
 
-    /// Database path
-    #[arg(long)]
-    database: Option<PathBuf>,
+    #[command(flatten)]
+    db: DatabaseArgs,
 
     /// Path to a YAML staker configuration
     #[arg(long)]
     staker_cfg: Option<PathBuf>,
 
-    /// If given, uses this JSON file to configure the network genesis rather than following the known testnet/mainnet genesis.
-    #[arg(long)]
-    override_genesis: Option<PathBuf>,
-
-    /// If set to true, default to the testnet. Otherwise, mainnet validation rules are used.
-    #[arg(long)]
-    testnet: bool,
-
     /// If set to true, runs a self-test by replaying the history from genesis, ensuring that everything is correct
     #[arg(long)]
     pub self_test: bool,
@@ -54,6 +45,46 @@ pub struct MainArgs {
     /// Create an in-memory coin index. **RPC endpoints that rely on this will be disabled if this is not set!**
     #[arg(long)]
     pub index_coins: bool,
+
+    /// If given, only keep the last N sealed states. Incompatible with --index-coins.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    prune_keep: Option<u64>,
+
+    /// Where a new database keeps its SMT forest: `meshanina`, `sqlite`, or `memory`.
+    #[arg(long)]
+    forest_backend: Option<ForestBackend>,
+
+    /// Memory budget, in MiB, of the cache of hot SMT nodes. 0 disables it.
+    #[arg(long, default_value_t = 256)]
+    forest_cache_mb: u64,
+
+    /// How long, in seconds, accepted transactions are journaled across restarts. 0 disables it.
+    #[arg(long, default_value_t = 3600)]
+    mempool_expiry_secs: u64,
+
+    /// If set, an empty node downloads the latest state instead of replaying every block.
+    #[arg(long)]
+    state_sync: bool,
+
+    /// Trusted `height:header_hash` that state sync starts from. Defaults to a built-in one.
+    #[arg(long)]
+    state_sync_checkpoint: Option<Checkpoint>,
+}
+
+/// Command-line arguments that locate a node's database.
+#[derive(Debug, Args)]
+pub struct DatabaseArgs {
+    /// Database path
+    #[arg(long)]
+    database: Option<PathBuf>,
+
+    /// If given, uses this JSON file to configure the network genesis rather than following the known testnet/mainnet genesis.
+    #[arg(long)]
+    override_genesis: Option<PathBuf>,
+
+    /// If set to true, default to the testnet. Otherwise, mainnet validation rules are used.
+    #[arg(long)]
+    testnet: bool,
 }
 
 /// Staker configuration, YAML-deserializable.
@@ -75,12 +106,7 @@ pub struct StakerConfig {
     pub target_fee_multiplier: u128,
 }
 
-impl MainArgs {
-    /// Gets the advertised IP.
-    pub fn advertise_addr(&self) -> Option<SocketAddr> {
-        self.advertise
-    }
-
+impl DatabaseArgs {
     /// Derives the genesis configuration from the arguments
     pub async fn genesis_config(&self) -> anyhow::Result<GenesisConfig> {
         if let Some(path) = &self.override_genesis {
@@ -96,13 +122,10 @@ impl MainArgs {
         }
     }
 
-    pub async fn storage(&self) -> anyhow::Result<Storage> {
+    /// Opens the storage, with the given options.
+    pub async fn storage(&self, config: StorageConfig) -> anyhow::Result<Storage> {
         let genesis = self.genesis_config().await?;
-
-        let database_default_path = dirs::home_dir().expect("no home dir?!").tap_mut(|p| {
-            p.push(".melnode/");
-        });
-        let database_base_path = self.database.clone().unwrap_or(database_default_path);
+        let database_base_path = self.database_path();
         let _history_path = database_base_path
             .clone()
             .tap_mut(|path| path.push("history"));
@@ -111,7 +134,7 @@ impl MainArgs {
             .tap_mut(|path| path.push("smt.db"));
 
         std::fs::create_dir_all(&database_base_path)?;
-        let storage = Storage::open(database_base_path, genesis)
+        let storage = Storage::open(database_base_path, genesis, config)
             .await
             .context("cannot make storage")?;
 
@@ -120,6 +143,68 @@ impl MainArgs {
         Ok(storage)
     }
 
+    /// Migrates the sqlite schema, returning the version it was at before.
+    pub async fn migrate_schema(&self) -> anyhow::Result<u32> {
+        let genesis = self.genesis_config().await?;
+        Storage::migrate_schema(self.database_path(), &genesis)
+    }
+
+    /// Opens the storage read-only, which works even while a node is using it.
+    pub async fn storage_readonly(&self) -> anyhow::Result<ReadStorage> {
+        let genesis = self.genesis_config().await?;
+        Storage::open_readonly(self.database_path(), genesis)
+            .await
+            .context("cannot open storage read-only")
+    }
+
+    fn database_path(&self) -> PathBuf {
+        let database_default_path = dirs::home_dir().expect("no home dir?!").tap_mut(|p| {
+            p.push(".melnode/");
+        });
+        self.database.clone().unwrap_or(database_default_path)
+    }
+}
+
+impl MainArgs {
+    /// Gets the advertised IP.
+    pub fn advertise_addr(&self) -> Option<SocketAddr> {
+        self.advertise
+    }
+
+    /// Derives the genesis configuration from the arguments
+    pub async fn genesis_config(&self) -> anyhow::Result<GenesisConfig> {
+        self.db.genesis_config().await
+    }
+
+    pub async fn storage(&self) -> anyhow::Result<Storage> {
+        if (self.prune_keep.is_some() || self.state_sync) && self.index_coins {
+            anyhow::bail!(
+                "the coin indexer needs the full history, so it cannot run on a pruned or state-synced node"
+            );
+        }
+        let config = StorageConfig {
+            prune_keep: self.prune_keep,
+            forest_backend: self.forest_backend,
+            forest_cache_bytes: self.forest_cache_mb << 20,
+            mempool_expiry: Duration::from_secs(self.mempool_expiry_secs),
+        };
+        self.db.storage(config).await
+    }
+
+    /// The checkpoint to state-sync from, if state sync is enabled.
+    pub async fn state_sync_checkpoint(&self) -> anyhow::Result<Option<Checkpoint>> {
+        if !self.state_sync {
+            return Ok(None);
+        }
+        if let Some(checkpoint) = &self.state_sync_checkpoint {
+            return Ok(Some(checkpoint.clone()));
+        }
+        let network = self.genesis_config().await?.network;
+        Ok(Some(melbootstrap::checkpoint_height(network).context(
+            "no built-in checkpoint for this network, so --state-sync-checkpoint must be given",
+        )?))
+    }
+
     /// Derives a list of bootstrap addresses
     pub async fn bootstrap(&self) -> anyhow::Result<Vec<SocketAddr>> {
         if !self.bootstrap.is_empty() {
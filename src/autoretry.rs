@@ -2,19 +2,53 @@ use std::{fmt::Debug, time::Duration};
 
 use futures_util::Future;
 
-/// Retries a function until it works, internally doing exponential backoff
-pub async fn autoretry<T, E: Debug, Fut: Future<Output = Result<T, E>>, Fun: FnMut() -> Fut>(
+/// How many times [autoretry] tries before giving up.
+const MAX_ATTEMPTS: u32 = 8;
+
+/// Errors that may go away if the operation is simply tried again.
+pub trait Transient {
+    fn is_transient(&self) -> bool;
+}
+
+/// Retries a function with exponential backoff while it fails with a transient error.
+pub async fn autoretry<
+    T,
+    E: Debug + Transient,
+    Fut: Future<Output = Result<T, E>>,
+    Fun: FnMut() -> Fut,
+>(
     mut f: Fun,
-) -> T {
+) -> Result<T, E> {
     let mut sleep_interval = Duration::from_millis(50);
+    let mut attempts = 1;
     loop {
         match f().await {
-            Ok(val) => return val,
-            Err(err) => {
+            Err(err) if err.is_transient() && attempts < MAX_ATTEMPTS => {
                 log::warn!("autoretrying due to {:?}", err);
                 smol::Timer::after(sleep_interval).await;
                 sleep_interval *= 2;
+                attempts += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Like [autoretry], but for blocking code, sleeping the thread between tries.
+pub fn autoretry_blocking<T, E: Debug + Transient>(
+    mut f: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    let mut sleep_interval = Duration::from_millis(50);
+    let mut attempts = 1;
+    loop {
+        match f() {
+            Err(err) if err.is_transient() && attempts < MAX_ATTEMPTS => {
+                log::warn!("autoretrying due to {:?}", err);
+                std::thread::sleep(sleep_interval);
+                sleep_interval *= 2;
+                attempts += 1;
             }
+            res => return res,
         }
     }
 }
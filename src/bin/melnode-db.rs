@@ -0,0 +1,124 @@
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand};
+use melnode::{args::DatabaseArgs, storage::ForestBackend};
+use melstructs::BlockHeight;
+use tmelcrypt::HashVal;
+
+/// Maintenance tools for a melnode database.
+#[derive(Parser)]
+struct Command {
+    #[command(flatten)]
+    db: DatabaseArgs,
+
+    #[command(subcommand)]
+    command: Sub,
+}
+
+#[derive(Subcommand)]
+enum Sub {
+    /// Copies the database into another database path, even while the node runs.
+    Backup(BackupArgs),
+    /// Exports the sealed state at some height into a snapshot file.
+    ExportSnapshot(ExportSnapshotArgs),
+    /// Bootstraps an empty database from a snapshot file.
+    ImportSnapshot(ImportSnapshotArgs),
+    /// Moves the SMT forest into another kind of backend, leaving the old files.
+    MigrateForest(MigrateForestArgs),
+    /// Rolls the database back to some height, deleting every later block.
+    Truncate(TruncateArgs),
+    /// Checks the database for missing or corrupt data, printing a JSON report.
+    Verify,
+}
+
+#[derive(Args)]
+struct BackupArgs {
+    /// Database path to write the backup into
+    #[arg(long)]
+    output: PathBuf,
+}
+
+#[derive(Args)]
+struct ExportSnapshotArgs {
+    /// Height of the state to export
+    #[arg(long)]
+    height: BlockHeight,
+
+    /// Path of the snapshot file to write
+    #[arg(long)]
+    output: PathBuf,
+}
+
+#[derive(Args)]
+struct ImportSnapshotArgs {
+    /// Path of the snapshot file to read
+    #[arg(long)]
+    input: PathBuf,
+
+    /// Hash of the snapshot's block header, obtained from a trusted source
+    #[arg(long)]
+    trusted_hash: HashVal,
+}
+
+#[derive(Args)]
+struct MigrateForestArgs {
+    /// Backend to move the forest into: `meshanina` or `sqlite`
+    #[arg(long)]
+    to: ForestBackend,
+}
+
+#[derive(Args)]
+struct TruncateArgs {
+    /// Height that becomes the highest stored block
+    #[arg(long)]
+    height: BlockHeight,
+}
+
+fn main() -> anyhow::Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("melnode=info"))
+        .init();
+    smolscale::block_on(async move {
+        let cmd = Command::parse();
+        match cmd.command {
+            Sub::Backup(args) => {
+                let storage = cmd.db.storage_readonly().await?;
+                let height = storage.backup(args.output).await?;
+                eprintln!("backed up the database up to height {height}");
+            }
+            Sub::ExportSnapshot(args) => {
+                let storage = cmd.db.storage_readonly().await?;
+                let count = storage.export_snapshot(args.height, args.output).await?;
+                eprintln!("exported state {} with {count} SMT nodes", args.height);
+            }
+            Sub::ImportSnapshot(args) => {
+                let storage = cmd.db.storage(Default::default()).await?;
+                let height = storage
+                    .import_snapshot(args.input, args.trusted_hash)
+                    .await?;
+                eprintln!("imported state {height}; the node will sync onwards from there");
+            }
+            Sub::MigrateForest(args) => {
+                let storage = cmd.db.storage(Default::default()).await?;
+                let count = storage.migrate_forest(args.to).await?;
+                eprintln!("copied {count} SMT nodes into the {} forest", args.to);
+            }
+            Sub::Truncate(args) => {
+                let storage = cmd.db.storage(Default::default()).await?;
+                let removed = storage.truncate_to(args.height).await?;
+                eprintln!(
+                    "removed {removed} blocks; the highest block is now {}",
+                    args.height
+                );
+            }
+            Sub::Verify => {
+                let storage = cmd.db.storage_readonly().await?;
+                let report = storage.verify().await?;
+                println!("{}", serde_json::to_string_pretty(&report)?);
+                if !report.problems.is_empty() {
+                    anyhow::bail!("found {} problems", report.problems.len());
+                }
+            }
+        }
+        Ok(())
+    })
+}
@@ -7,7 +7,7 @@ use melnet2::{wire::http::HttpBackhaul, Swarm};
 use melprot::{Client, CoinChange, NodeRpcClient};
 use melstructs::{BlockHeight, CoinID};
 
-#[cfg(feature = "dhat-heap")]
+#[cfg(feature = "dhat")]
 #[global_allocator]
 static ALLOC: dhat::Alloc = dhat::Alloc;
 
@@ -28,7 +28,7 @@ pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 /// Runs the main function for a node.
 pub async fn main_async(opt: MainArgs) -> anyhow::Result<()> {
-    #[cfg(feature = "dhat-heap")]
+    #[cfg(feature = "dhat")]
     let _profiler = dhat::Profiler::new_heap();
 
     log::info!("melnode v{} initializing...", VERSION);
@@ -54,6 +54,7 @@ pub async fn main_async(opt: MainArgs) -> anyhow::Result<()> {
         opt.advertise_addr(),
         storage.clone(),
         opt.index_coins,
+        opt.state_sync_checkpoint().await?,
         swarm.clone(),
     )
     .await?;
@@ -81,7 +82,7 @@ pub async fn main_async(opt: MainArgs) -> anyhow::Result<()> {
                     .get_state(BlockHeight(9))
                     .await
                     .context("no block 1")?;
-                let last_height = storage.highest_height().await.0;
+                let last_height = storage.highest_height().await?.0;
                 for bh in 10..=last_height {
                     let bh = BlockHeight(bh);
                     // let blk = storage.get_state(bh).await.context("no block")?.to_block();
@@ -131,13 +132,13 @@ pub async fn main_async(opt: MainArgs) -> anyhow::Result<()> {
         .detach();
     }
 
-    // #[cfg(feature = "dhat-heap")]
+    // #[cfg(feature = "dhat")]
     // for i in 0..300 {
     //     smol::Timer::after(Duration::from_secs(1)).await;
     //     dbg!(i);
     // }
 
-    #[cfg(not(feature = "dhat-heap"))]
+    #[cfg(not(feature = "dhat"))]
     let _: u64 = smol::future::pending().await;
 
     Ok(())
@@ -1,7 +1,11 @@
 mod blksync;
+pub mod ext;
 mod indexer;
 
-use crate::{node::blksync::attempt_blksync, storage::Storage};
+use crate::{
+    node::blksync::{attempt_blksync, attempt_state_sync},
+    storage::{Storage, StorageError, StorageResultExt},
+};
 
 use anyhow::Context;
 use async_trait::async_trait;
@@ -9,17 +13,21 @@ use base64::Engine;
 use lru::LruCache;
 use melblkidx::{CoinInfo, Indexer};
 use melnet2::{wire::http::HttpBackhaul, Backhaul, Swarm};
+use nanorpc::{OrService, RpcService, ServerError};
 use novasmt::{CompressedProof, Database, InMemoryCas, Tree};
 use once_cell::sync::Lazy;
 use parking_lot::Mutex;
 
 use melstf::SmtMapping;
 use melstructs::{
-    AbbrBlock, Address, Block, BlockHeight, CoinID, ConsensusProof, NetID, Transaction, TxHash,
+    AbbrBlock, Address, Block, BlockHeight, Checkpoint, CoinID, ConsensusProof, NetID, Transaction,
+    TxHash,
 };
+use serde::{de::DeserializeOwned, Serialize};
 use std::{
     collections::BTreeMap,
     net::SocketAddr,
+    sync::Arc,
     time::{Duration, Instant},
 };
 use stdcode::StdcodeSerializeExt;
@@ -32,7 +40,7 @@ use melprot::{
 use smol_timeout::TimeoutExt;
 use tmelcrypt::{HashVal, Hashable};
 
-use self::indexer::WrappedIndexer;
+use self::{ext::NodeExtService, indexer::WrappedIndexer};
 
 /// An actor implementing the node P2P protocol, common for both replicas and stakers..
 pub struct Node {
@@ -40,7 +48,7 @@ pub struct Node {
 }
 
 impl Node {
-    /// Creates a new Node.
+    /// Creates a new Node, state-syncing an empty storage first if given a checkpoint.
     pub async fn start(
         netid: NetID,
         listen_addr: SocketAddr,
@@ -48,33 +56,45 @@ impl Node {
         advertise_addr: Option<SocketAddr>,
         storage: Storage,
         index_coins: bool,
+        state_sync: Option<Checkpoint>,
         swarm: Swarm<HttpBackhaul, NodeRpcClient>,
     ) -> anyhow::Result<Self> {
+        let rpc = NodeRpcImpl::start(
+            swarm.clone(),
+            listen_addr,
+            netid,
+            storage.clone(),
+            index_coins,
+        )
+        .await?;
         // This is all we need to do since start_listen does not block.
         log::debug!("starting to listen at {}", listen_addr);
         swarm
             .start_listen(
                 listen_addr.to_string().into(),
                 advertise_addr.map(|addr| addr.to_string().into()),
-                NodeRpcService(
-                    NodeRpcImpl::start(
-                        swarm.clone(),
-                        listen_addr,
-                        netid,
-                        storage.clone(),
-                        index_coins,
-                    )
-                    .await?,
-                ),
+                StorageErrorGuard {
+                    inner: OrService::new(NodeRpcService(rpc.clone()), NodeExtService(rpc.clone())),
+                    rpc,
+                },
             )
             .await?;
 
-        let _blksync_task = smolscale::spawn(blksync_loop(netid, swarm, storage));
+        let _blksync_task = smolscale::spawn(blksync_loop(netid, swarm, storage, state_sync));
         Ok(Self { _blksync_task })
     }
 }
 
-async fn blksync_loop(_netid: NetID, swarm: Swarm<HttpBackhaul, NodeRpcClient>, storage: Storage) {
+/// How many state sync failures before falling back to block sync.
+const STATE_SYNC_ATTEMPTS: usize = 10;
+
+async fn blksync_loop(
+    netid: NetID,
+    swarm: Swarm<HttpBackhaul, NodeRpcClient>,
+    storage: Storage,
+    mut state_sync: Option<Checkpoint>,
+) {
+    let mut state_sync_failures = 0;
     loop {
         let gap_time: Duration = Duration::from_secs_f64(fastrand::f64() * 1.0);
         let routes = swarm.routes().await;
@@ -84,13 +104,26 @@ async fn blksync_loop(_netid: NetID, swarm: Swarm<HttpBackhaul, NodeRpcClient>,
             let fallible_part = async {
                 let client = swarm.connect(peer.clone()).await?;
                 let addr: SocketAddr = peer.clone().to_string().parse()?;
+                if let Some(checkpoint) = &state_sync {
+                    if storage.highest_height().await?.0 == 0 {
+                        if let Err(err) =
+                            attempt_state_sync(addr, netid, checkpoint.clone(), &storage).await
+                        {
+                            state_sync_failures += 1;
+                            return Err(err);
+                        }
+                    }
+                }
                 let res = attempt_blksync(addr, &client, &storage).await?;
                 anyhow::Ok(res)
             };
             match fallible_part.await {
                 Err(e) => {
                     log::warn!("failed to blksync with {}: {:?}", peer, e);
-                    log::warn!("last state: {:?}", storage.highest_state().await.header());
+                    log::warn!(
+                        "last state: {:?}",
+                        storage.highest_state().await.map(|s| s.header())
+                    );
                 }
                 Ok(blklen) => {
                     if blklen > 0 {
@@ -98,21 +131,98 @@ async fn blksync_loop(_netid: NetID, swarm: Swarm<HttpBackhaul, NodeRpcClient>,
                     }
                 }
             }
+            if state_sync.is_some() && state_sync_failures >= STATE_SYNC_ATTEMPTS {
+                log::warn!(
+                    "state sync failed {} times, falling back to block sync",
+                    state_sync_failures
+                );
+                state_sync = None;
+            }
         }
         smol::Timer::after(gap_time).await;
     }
 }
 
+/// Answers the storage-backed RPC methods with explicit errors rather than `null`.
+struct StorageErrorGuard<S: RpcService> {
+    inner: S,
+    rpc: NodeRpcImpl,
+}
+
+#[async_trait]
+impl<S: RpcService> RpcService for StorageErrorGuard<S> {
+    async fn respond(
+        &self,
+        method: &str,
+        params: Vec<serde_json::Value>,
+    ) -> Option<Result<serde_json::Value, ServerError>> {
+        if let Some(res) = self.try_respond(method, &params).await {
+            return Some(res);
+        }
+        self.inner.respond(method, params).await
+    }
+}
+
+impl<S: RpcService> StorageErrorGuard<S> {
+    /// Answers the methods that can hit storage errors, leaving the rest to `inner`.
+    async fn try_respond(
+        &self,
+        method: &str,
+        params: &[serde_json::Value],
+    ) -> Option<Result<serde_json::Value, ServerError>> {
+        fn param<T: DeserializeOwned>(params: &[serde_json::Value], i: usize) -> Option<T> {
+            serde_json::from_value(params.get(i)?.clone()).ok()
+        }
+        let rpc = &self.rpc;
+        Some(match method {
+            "get_abbr_block" => reply(rpc.try_get_abbr_block(param(params, 0)?).await),
+            "get_summary" => reply(rpc.try_get_summary().await),
+            "get_block" => reply(rpc.try_get_block(param(params, 0)?).await),
+            "get_lz4_blocks" => reply(
+                rpc.try_get_lz4_blocks(param(params, 0)?, param(params, 1)?)
+                    .await,
+            ),
+            "get_smt_branch" => reply(
+                rpc.try_get_smt_branch(param(params, 0)?, param(params, 1)?, param(params, 2)?)
+                    .await,
+            ),
+            "get_stakers_raw" => reply(rpc.try_get_stakers_raw(param(params, 0)?).await),
+            _ => return None,
+        })
+    }
+}
+
+/// Turns a handler's result into a JSON-RPC response, with NotFound as `null`.
+fn reply<T: Serialize>(res: Result<T, StorageError>) -> Result<serde_json::Value, ServerError> {
+    match res.optional() {
+        Ok(val) => Ok(serde_json::to_value(val).expect("serialization failed")),
+        Err(err) => {
+            let code = match err {
+                StorageError::Pruned(_) => 404,
+                StorageError::Busy(_) => 503,
+                StorageError::BadRequest(_) => 400,
+                _ => 500,
+            };
+            Err(ServerError {
+                code,
+                message: err.to_string(),
+                details: serde_json::to_value(&err).expect("serialization failed"),
+            })
+        }
+    }
+}
+
 // This struct is responsible for obtaining any "state" needed for the implementation of the RPC business logic.
+#[derive(Clone)]
 pub struct NodeRpcImpl {
     network: NetID,
     storage: Storage,
-    recent: Mutex<LruCache<TxHash, Instant>>,
-    summary: Mutex<LruCache<BlockHeight, StateSummary>>,
-    coin_smts: Mutex<LruCache<BlockHeight, Tree<InMemoryCas>>>,
+    recent: Arc<Mutex<LruCache<TxHash, Instant>>>,
+    summary: Arc<Mutex<LruCache<BlockHeight, StateSummary>>>,
+    coin_smts: Arc<Mutex<LruCache<BlockHeight, Tree<InMemoryCas>>>>,
     abbr_block_cache: moka::sync::Cache<BlockHeight, (AbbrBlock, ConsensusProof)>,
     swarm: Swarm<HttpBackhaul, NodeRpcClient>,
-    indexer: Option<WrappedIndexer>,
+    indexer: Option<Arc<WrappedIndexer>>,
 }
 
 impl NodeRpcImpl {
@@ -124,32 +234,32 @@ impl NodeRpcImpl {
         index_coins: bool,
     ) -> anyhow::Result<Self> {
         let indexer = if index_coins {
-            Some(WrappedIndexer::start(network, storage.clone(), listen_addr).await?)
+            Some(
+                WrappedIndexer::start(network, storage.clone(), listen_addr)
+                    .await?
+                    .into(),
+            )
         } else {
             None
         };
         Ok(Self {
             network,
             storage,
-            recent: LruCache::new(1000).into(),
-            coin_smts: LruCache::new(100).into(),
-            summary: LruCache::new(10).into(),
+            recent: Arc::new(LruCache::new(1000).into()),
+            coin_smts: Arc::new(LruCache::new(100).into()),
+            summary: Arc::new(LruCache::new(10).into()),
             swarm,
             abbr_block_cache: moka::sync::Cache::new(1000),
             indexer,
         })
     }
 
-    async fn get_coin_tree(&self, height: BlockHeight) -> anyhow::Result<Tree<InMemoryCas>> {
+    async fn get_coin_tree(&self, height: BlockHeight) -> Result<Tree<InMemoryCas>, StorageError> {
         let otree = self.coin_smts.lock().get(&height).cloned();
         if let Some(v) = otree {
             Ok(v)
         } else {
-            let state = self
-                .storage
-                .get_state(height)
-                .await
-                .context(format!("block {} not confirmed yet", height))?;
+            let state = self.storage.get_state(height).await?;
             let mut mm = SmtMapping::new(
                 Database::new(InMemoryCas::default())
                     .get_tree(Default::default())
@@ -168,7 +278,7 @@ impl NodeRpcImpl {
     async fn get_indexer(&self) -> Option<&Indexer> {
         if let Some(indexer) = self.indexer.as_ref() {
             let indexer = indexer.inner();
-            let height = self.storage.highest_height().await;
+            let height = self.storage.highest_height().await.ok()?;
             while indexer.max_height() < height {
                 log::warn!("waiting for {height} to be available at the indexer...");
                 smol::Timer::after(Duration::from_secs(1)).await;
@@ -178,11 +288,119 @@ impl NodeRpcImpl {
             None
         }
     }
+
+    async fn try_get_abbr_block(
+        &self,
+        height: BlockHeight,
+    ) -> Result<(AbbrBlock, ConsensusProof), StorageError> {
+        if let Some(c) = self.abbr_block_cache.get(&height) {
+            return Ok(c);
+        }
+        log::trace!("handling get_abbr_block({})", height);
+        let block = self.storage.get_block(height).await?;
+        let proof = self.storage.get_consensus(height).await?;
+        let summ = (block.abbreviate(), proof);
+        self.abbr_block_cache.insert(height, summ.clone());
+        Ok(summ)
+    }
+
+    async fn try_get_summary(&self) -> Result<StateSummary, StorageError> {
+        log::trace!("handling get_summary()");
+        let header = self.storage.highest_header();
+        let res = self.summary.lock().get(&header.height).cloned();
+        if let Some(res) = res {
+            Ok(res)
+        } else {
+            let proof = self
+                .storage
+                .get_consensus(header.height)
+                .await
+                .optional()?
+                .unwrap_or_default();
+            let summary = StateSummary {
+                netid: self.network,
+                height: header.height,
+                header,
+                proof,
+            };
+            self.summary.lock().push(header.height, summary.clone());
+            Ok(summary)
+        }
+    }
+
+    async fn try_get_block(&self, height: BlockHeight) -> Result<Block, StorageError> {
+        log::trace!("handling get_state({})", height);
+        self.storage.get_block(height).await
+    }
+
+    async fn try_get_lz4_blocks(
+        &self,
+        height: BlockHeight,
+        size_limit: usize,
+    ) -> Result<String, StorageError> {
+        log::debug!("get_lz4_blocks({height}, {size_limit})");
+        let size_limit = size_limit.min(10_000_000);
+        // TODO: limit the *compressed* size. But this is fine because compression makes stuff smoller
+        let blocks = self.storage.get_raw_blocks(height, size_limit).await?;
+
+        // this is the stdcode of a (Vec<Block>, Vec<ConsensusProof>), which is just each vector's length followed by its elements, so the stored encodings go in as they are
+        let mut encoded = (blocks.len() as u64).stdcode();
+        for (block, _) in blocks.iter() {
+            encoded.extend_from_slice(block);
+        }
+        encoded.extend((blocks.len() as u64).stdcode());
+        for (_, proof) in blocks.iter() {
+            encoded.extend_from_slice(proof);
+        }
+        let compressed = lz4_flex::compress_prepend_size(&encoded);
+        Ok(base64::engine::general_purpose::STANDARD_NO_PAD.encode(compressed))
+    }
+
+    async fn try_get_smt_branch(
+        &self,
+        height: BlockHeight,
+        elem: Substate,
+        key: HashVal,
+    ) -> Result<(Vec<u8>, CompressedProof), StorageError> {
+        log::trace!("handling get_smt_branch({}, {:?})", height, elem);
+        let state = self.storage.get_state(height).await?;
+        let ctree = self.get_coin_tree(height).await?;
+        let coins_smt = state.raw_coins_smt();
+        let history_smt = state.raw_history_smt();
+        let pools_smt = state.raw_pools_smt();
+
+        let (v, proof) = match elem {
+            Substate::Coins => coins_smt.get_with_proof(key.0),
+            Substate::History => history_smt.get_with_proof(key.0),
+            Substate::Pools => pools_smt.get_with_proof(key.0),
+            Substate::Stakes => {
+                return Err(StorageError::BadRequest(
+                    "the stakes SMT no longer exists".into(),
+                ))
+            }
+            Substate::Transactions => ctree.get_with_proof(key.0),
+        };
+        Ok((v.to_vec(), proof.compress()))
+    }
+
+    async fn try_get_stakers_raw(
+        &self,
+        height: BlockHeight,
+    ) -> Result<BTreeMap<HashVal, Vec<u8>>, StorageError> {
+        let state = self.storage.get_state(height).await?;
+        // Note, the returned HashVal is >> HASHED AGAIN << because this is supposed to be compatible with the old SmtMapping encoding, where the key to the `stakes` SMT is the *hash of the transaction hash* due to a quirk.
+        Ok(state
+            .raw_stakes()
+            .iter()
+            .map(|(k, v)| (k.0.hash(), v.stdcode()))
+            .collect())
+    }
 }
 
 /// Global TCP backhaul for node connections
 static TCP_BACKHAUL: Lazy<HttpBackhaul> = Lazy::new(HttpBackhaul::new);
 
+// The storage-backed methods are normally answered by [StorageErrorGuard] through their `try_` versions; these plain versions flatten every error into `None`.
 #[async_trait]
 impl NodeRpcProtocol for NodeRpcImpl {
     async fn send_tx(&self, tx: Transaction) -> Result<(), TransactionError> {
@@ -205,6 +423,10 @@ impl NodeRpcProtocol for NodeRpcImpl {
                 TransactionError::Invalid(e.to_string())
             })?;
 
+        if let Err(err) = self.storage.journal_tx(&tx).await {
+            log::warn!("cannot journal tx {}: {:?}", tx.hash_nosigs(), err);
+        }
+
         log::debug!(
             "txhash {}.. inserted ({:?} applying)",
             &tx.hash_nosigs().to_string()[..10],
@@ -231,88 +453,32 @@ impl NodeRpcProtocol for NodeRpcImpl {
     }
 
     async fn get_abbr_block(&self, height: BlockHeight) -> Option<(AbbrBlock, ConsensusProof)> {
-        if let Some(c) = self.abbr_block_cache.get(&height) {
-            return Some(c);
-        }
-        log::trace!("handling get_abbr_block({})", height);
-        let block = self.storage.get_block(height).await?;
-        let proof = self.storage.get_consensus(height).await?;
-        let summ = (block.abbreviate(), proof);
-        self.abbr_block_cache.insert(height, summ.clone());
-        Some(summ)
+        self.try_get_abbr_block(height).await.ok()
     }
 
     async fn get_summary(&self) -> StateSummary {
-        log::trace!("handling get_summary()");
-        let highest = self.storage.highest_state().await;
-        let header = highest.header();
-        let res = self.summary.lock().get(&header.height).cloned();
-        if let Some(res) = res {
-            res
-        } else {
-            let proof = self
-                .storage
-                .get_consensus(header.height)
-                .await
-                .unwrap_or_default();
-            let summary = StateSummary {
-                netid: self.network,
-                height: header.height,
-                header,
-                proof,
-            };
-            self.summary.lock().push(header.height, summary.clone());
-            summary
+        match self.try_get_summary().await {
+            Ok(summary) => summary,
+            // only the consensus proof can fail to read, and a summary without one is still worth answering with
+            Err(err) => {
+                log::warn!("summarizing the highest state without its consensus proof: {err}");
+                let header = self.storage.highest_header();
+                StateSummary {
+                    netid: self.network,
+                    height: header.height,
+                    header,
+                    proof: Default::default(),
+                }
+            }
         }
     }
 
     async fn get_block(&self, height: BlockHeight) -> Option<Block> {
-        log::trace!("handling get_state({})", height);
-        self.storage.get_block(height).await
+        self.try_get_block(height).await.ok()
     }
 
     async fn get_lz4_blocks(&self, height: BlockHeight, size_limit: usize) -> Option<String> {
-        log::debug!("get_lz4_blocks({height}, {size_limit})");
-        let size_limit = size_limit.min(10_000_000);
-        // TODO: limit the *compressed* size. But this is fine because compression makes stuff smoller
-        let mut total_count = 0;
-        let mut accum = vec![];
-        let mut proof_accum = vec![];
-
-        let mut height = height;
-        while total_count <= size_limit {
-            if let Some(block) = self.get_block(height).await {
-                match self.storage.get_consensus(height).await {
-                    Some(proof) => {
-                        total_count += block.stdcode().len();
-                        total_count += proof.stdcode().len();
-
-                        accum.push(block);
-                        proof_accum.push(proof);
-
-                        if total_count > size_limit {
-                            log::info!("BATCH IS DONE");
-                            if accum.len() > 1 {
-                                accum.pop();
-                            }
-                        }
-
-                        height += BlockHeight(1);
-                    }
-                    _ => {
-                        log::warn!("no proof stored for height {}", height);
-                    }
-                }
-            } else if accum.is_empty() {
-                log::warn!("no stored block for height: {:?}", height);
-                return None;
-            } else {
-                break;
-            }
-        }
-
-        let compressed = lz4_flex::compress_prepend_size(&(accum, proof_accum).stdcode());
-        Some(base64::engine::general_purpose::STANDARD_NO_PAD.encode(compressed))
+        self.try_get_lz4_blocks(height, size_limit).await.ok()
     }
 
     async fn get_smt_branch(
@@ -321,33 +487,11 @@ impl NodeRpcProtocol for NodeRpcImpl {
         elem: Substate,
         key: HashVal,
     ) -> Option<(Vec<u8>, CompressedProof)> {
-        log::trace!("handling get_smt_branch({}, {:?})", height, elem);
-        let state = self.storage.get_state(height).await?;
-        let ctree = self.get_coin_tree(height).await.ok()?;
-        let coins_smt = state.raw_coins_smt();
-        let history_smt = state.raw_history_smt();
-        let pools_smt = state.raw_pools_smt();
-
-        let (v, proof) = match elem {
-            Substate::Coins => coins_smt.get_with_proof(key.0),
-            Substate::History => history_smt.get_with_proof(key.0),
-            Substate::Pools => pools_smt.get_with_proof(key.0),
-            Substate::Stakes => todo!("no longer relevant"),
-            Substate::Transactions => ctree.get_with_proof(key.0),
-        };
-        Some((v.to_vec(), proof.compress()))
+        self.try_get_smt_branch(height, elem, key).await.ok()
     }
 
     async fn get_stakers_raw(&self, height: BlockHeight) -> Option<BTreeMap<HashVal, Vec<u8>>> {
-        let state = self.storage.get_state(height).await?;
-        // Note, the returned HashVal is >> HASHED AGAIN << because this is supposed to be compatible with the old SmtMapping encoding, where the key to the `stakes` SMT is the *hash of the transaction hash* due to a quirk.
-        Some(
-            state
-                .raw_stakes()
-                .iter()
-                .map(|(k, v)| (k.0.hash(), v.stdcode()))
-                .collect(),
-        )
+        self.try_get_stakers_raw(height).await.ok()
     }
 
     async fn get_some_coins(&self, height: BlockHeight, covhash: Address) -> Option<Vec<CoinID>> {
@@ -371,7 +515,7 @@ impl NodeRpcProtocol for NodeRpcImpl {
         covhash: Address,
     ) -> Option<Vec<CoinChange>> {
         log::debug!("get_coin_changes({height}, {covhash})");
-        self.storage.get_block(height).await?;
+        self.storage.get_block(height).await.ok()?;
         let indexer = self.get_indexer().await?;
         // get coins 1 block below the given height
         let deleted_coins: Vec<CoinInfo> = indexer
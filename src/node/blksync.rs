@@ -1,14 +1,21 @@
-use crate::storage::Storage;
+use crate::storage::{node_hash, SmtImport, SnapshotMeta, Storage};
 use anyhow::Context;
 use base64::Engine;
 use futures_util::stream::{StreamExt, TryStreamExt};
+use melnet2::Backhaul;
 use melprot::NodeRpcClient;
-use melstructs::{Block, BlockHeight, ConsensusProof};
+use melstructs::{Block, BlockHeight, Checkpoint, ConsensusProof, NetID};
 use smol_timeout::TimeoutExt;
 use std::{
     net::SocketAddr,
     time::{Duration, Instant},
 };
+use tmelcrypt::HashVal;
+
+use super::{
+    ext::{NodeExtClient, MAX_SMT_NODES},
+    TCP_BACKHAUL,
+};
 
 /// Attempts a sync using the given given node client.
 pub async fn attempt_blksync(
@@ -28,7 +35,7 @@ pub async fn attempt_blksync(
         .context("cannot get their highest block")?
         .height;
 
-    let my_highest = storage.highest_height().await;
+    let my_highest = storage.highest_height().await?;
     if their_highest <= my_highest {
         return Ok(0);
     }
@@ -63,7 +70,6 @@ pub async fn attempt_blksync(
             _ => anyhow::bail!("missing block {height}"),
         };
 
-        let mut last_applied_height = height;
         log::info!(
             "fully resolved blocks {}..{} from peer {} in {:.2}ms",
             blocks.first().map(|b| b.header.height).unwrap_or_default(),
@@ -71,22 +77,28 @@ pub async fn attempt_blksync(
             addr,
             start.elapsed().as_secs_f64() * 1000.0
         );
-        for (block, cproof) in blocks.iter().zip(cproofs) {
-            // validate before applying
-            if block.header.height != last_applied_height {
-                anyhow::bail!("wanted block {}, but got {}", height, block.header.height);
+        // validate before applying
+        for (i, block) in blocks.iter().enumerate() {
+            let wanted = height + BlockHeight(i as u64);
+            if block.header.height != wanted {
+                anyhow::bail!("wanted block {}, but got {}", wanted, block.header.height);
             }
-
-            storage
-                .apply_block(block.clone(), cproof)
-                .await
-                .context("could not apply a resolved block")?;
-            num_blocks_applied += 1;
-
-            last_applied_height += BlockHeight(1);
         }
+        if blocks.len() != cproofs.len() {
+            anyhow::bail!(
+                "got {} blocks but {} consensus proofs",
+                blocks.len(),
+                cproofs.len()
+            );
+        }
+        let count = blocks.len();
+        storage
+            .apply_blocks(blocks.into_iter().zip(cproofs).collect())
+            .await
+            .context("could not apply a resolved batch of blocks")?;
+        num_blocks_applied += count;
 
-        height += BlockHeight(blocks.len() as u64);
+        height += BlockHeight(count as u64);
     }
 
     Ok(num_blocks_applied)
@@ -105,7 +117,7 @@ pub async fn attempt_blksync_legacy(
         .context("timed out getting summary")?
         .context("cannot get their highest block")?
         .height;
-    let my_highest = storage.highest_height().await;
+    let my_highest = storage.highest_height().await?;
     if their_highest <= my_highest {
         return Ok(0);
     }
@@ -160,3 +172,92 @@ pub async fn attempt_blksync_legacy(
     }
     Ok(toret)
 }
+
+/// State-syncs an empty storage to a peer's latest state, verified from a checkpoint.
+pub async fn attempt_state_sync(
+    addr: SocketAddr,
+    netid: NetID,
+    checkpoint: Checkpoint,
+    storage: &Storage,
+) -> anyhow::Result<BlockHeight> {
+    let client = melprot::Client::new(
+        netid,
+        NodeRpcClient(TCP_BACKHAUL.connect(addr.to_string().into()).await?),
+    );
+    client.trust(checkpoint);
+    let snapshot = client
+        .latest_snapshot()
+        .timeout(Duration::from_secs(60))
+        .await
+        .context("timed out verifying the latest header")??;
+    let header = snapshot.current_header();
+    let height = header.height;
+    log::info!("state syncing to height {height} from peer {addr}");
+
+    let raw = snapshot.get_raw();
+    let block = raw
+        .get_block(height)
+        .await?
+        .context("peer does not have the block for its latest state")?;
+    if block.header != header {
+        anyhow::bail!("peer gave us a block that does not match the verified header");
+    }
+    let (_, cproof) = raw
+        .get_abbr_block(height)
+        .await?
+        .context("peer does not have the consensus proof for its latest state")?;
+    let ext = NodeExtClient(TCP_BACKHAUL.connect(addr.to_string().into()).await?);
+    let stakes = ext
+        .get_stakes(height)
+        .await??
+        .context("peer does not have the stakes for its latest state")?;
+
+    // walk the trees top-down, only asking for nodes we don't already have; nodes are only stored once their whole subtree has been checked, so an interrupted sync picks up where it left off
+    let start = Instant::now();
+    let mut import = SmtImport::new(
+        storage.forest().storage(),
+        [
+            header.coins_hash.0,
+            header.history_hash.0,
+            header.pools_hash.0,
+        ],
+    );
+    let mut downloaded: u64 = 0;
+    loop {
+        let wanted = import.next_wanted(MAX_SMT_NODES);
+        if wanted.is_empty() {
+            break;
+        }
+        let nodes = ext
+            .get_smt_nodes(wanted.iter().map(|h| HashVal(*h)).collect())
+            .timeout(Duration::from_secs(30))
+            .await
+            .context("timed out getting SMT nodes")??;
+        for node in nodes {
+            let node = base64::engine::general_purpose::STANDARD_NO_PAD.decode(node.as_bytes())?;
+            let hash = node_hash(&node).context("peer gave us a malformed SMT node")?;
+            if !wanted.contains(&hash) || !import.expects(&hash) {
+                anyhow::bail!("peer gave us an SMT node we did not ask for");
+            }
+            import.insert(node).context("peer gave us a bad SMT node")?;
+            downloaded += 1;
+        }
+        if let Some(missing) = wanted.iter().find(|h| import.expects(h)) {
+            anyhow::bail!("peer is missing SMT node {}", hex::encode(missing));
+        }
+        log::debug!("downloaded {downloaded} SMT nodes so far");
+    }
+    import.finish()?;
+    log::info!(
+        "downloaded {downloaded} SMT nodes from peer {addr} in {:.2}s",
+        start.elapsed().as_secs_f64()
+    );
+
+    Ok(storage
+        .import_state(SnapshotMeta {
+            block,
+            cproof,
+            stakes,
+        })
+        .await?)
+}
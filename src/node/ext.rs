@@ -0,0 +1,176 @@
+use crate::storage::{MempoolStats, PendingTxSummary, StorageError, StorageResultExt};
+
+use async_trait::async_trait;
+use base64::Engine;
+use melstructs::{Block, BlockHeight, ConsensusProof, StakeDoc, Transaction, TxHash};
+use nanorpc::nanorpc_derive;
+use novasmt::{dense::DenseMerkleTree, CompressedProof, ContentAddrStore};
+use serde::{Deserialize, Serialize};
+use stdcode::StdcodeSerializeExt;
+use tmelcrypt::{HashVal, Hashable};
+
+use super::NodeRpcImpl;
+
+/// Most SMT nodes served by one [NodeExtProtocol::get_smt_nodes] call.
+pub const MAX_SMT_NODES: usize = 1000;
+
+/// melnode-specific RPC endpoints, served next to [melprot::NodeRpcProtocol].
+#[nanorpc_derive]
+#[async_trait]
+pub trait NodeExtProtocol: Send + Sync {
+    /// Gets up to [MAX_SMT_NODES] raw SMT nodes by hash, base64-encoded, skipping missing ones.
+    async fn get_smt_nodes(&self, hashes: Vec<HashVal>) -> Vec<String>;
+
+    /// Gets every live stake at a height, keyed by the *unhashed* transaction hash.
+    async fn get_stakes(
+        &self,
+        height: BlockHeight,
+    ) -> Result<Option<Vec<(TxHash, StakeDoc)>>, StorageError>;
+
+    /// Looks up a confirmed transaction by its hash.
+    async fn get_transaction(
+        &self,
+        txhash: TxHash,
+    ) -> Result<Option<TransactionInfo>, StorageError>;
+
+    /// Looks up a block and its consensus proof by header hash.
+    async fn get_block_by_hash(
+        &self,
+        hash: HashVal,
+    ) -> Result<Option<(Block, ConsensusProof)>, StorageError>;
+
+    /// Lists the pending transactions, in order of arrival.
+    async fn get_mempool_txx(&self) -> Vec<PendingTxSummary>;
+
+    /// Gets a pending transaction by its hash.
+    async fn get_mempool_tx(&self, txhash: TxHash) -> Option<Transaction>;
+
+    /// Reports how full the mempool is.
+    async fn get_mempool_stats(&self) -> MempoolStats;
+}
+
+/// A confirmed transaction, as returned by [NodeExtProtocol::get_transaction].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionInfo {
+    pub transaction: Transaction,
+    pub height: BlockHeight,
+    /// Position of the transaction in its block, ordered by hash.
+    pub index: u32,
+    /// Proof that the transaction is in the block's `transactions_hash`.
+    pub proof: TransactionProof,
+}
+
+/// A transaction inclusion proof, whose format depends on TIP-908.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum TransactionProof {
+    /// Before TIP-908: an SMT from `hash(stdcode(txhash))` to `stdcode(tx)`.
+    Smt(CompressedProof),
+    /// After TIP-908: a dense Merkle tree over sorted `txhash ++ hash(stdcode(tx))` leaves.
+    Dense(Vec<HashVal>),
+}
+
+#[async_trait]
+impl NodeExtProtocol for NodeRpcImpl {
+    async fn get_smt_nodes(&self, hashes: Vec<HashVal>) -> Vec<String> {
+        log::trace!("handling get_smt_nodes({})", hashes.len());
+        let cas = self.storage.forest().storage();
+        hashes
+            .iter()
+            .take(MAX_SMT_NODES)
+            .filter_map(|hash| cas.get(&hash.0))
+            .map(|node| base64::engine::general_purpose::STANDARD_NO_PAD.encode(node))
+            .collect()
+    }
+
+    async fn get_stakes(
+        &self,
+        height: BlockHeight,
+    ) -> Result<Option<Vec<(TxHash, StakeDoc)>>, StorageError> {
+        log::trace!("handling get_stakes({})", height);
+        let stakes = self
+            .storage
+            .get_state(height)
+            .await
+            .optional()?
+            .map(|state| {
+                state
+                    .raw_stakes()
+                    .iter()
+                    .map(|(txhash, stake)| (*txhash, *stake))
+                    .collect()
+            });
+        Ok(stakes)
+    }
+
+    async fn get_transaction(
+        &self,
+        txhash: TxHash,
+    ) -> Result<Option<TransactionInfo>, StorageError> {
+        log::trace!("handling get_transaction({})", txhash);
+        let Some((transaction, height, index)) =
+            self.storage.get_transaction(txhash).await.optional()?
+        else {
+            return Ok(None);
+        };
+        let block = self.storage.get_block(height).await?;
+        let dense = DenseMerkleTree::new(&dense_leaves(&block));
+        let proof = if HashVal(dense.root_hash()) == block.header.transactions_hash {
+            TransactionProof::Dense(
+                dense
+                    .proof(index as usize)
+                    .into_iter()
+                    .map(HashVal)
+                    .collect(),
+            )
+        } else {
+            // the transactions SMT is an SmtMapping, so its keys are the hashes of the serialized transaction hashes
+            let tree = self.get_coin_tree(height).await?;
+            let (_, proof) = tree.get_with_proof(tmelcrypt::hash_single(txhash.stdcode()).0);
+            TransactionProof::Smt(proof.compress())
+        };
+        Ok(Some(TransactionInfo {
+            transaction,
+            height,
+            index,
+            proof,
+        }))
+    }
+
+    async fn get_block_by_hash(
+        &self,
+        hash: HashVal,
+    ) -> Result<Option<(Block, ConsensusProof)>, StorageError> {
+        log::trace!("handling get_block_by_hash({})", hash);
+        self.storage.get_block_by_hash(hash).await.optional()
+    }
+
+    async fn get_mempool_txx(&self) -> Vec<PendingTxSummary> {
+        log::trace!("handling get_mempool_txx()");
+        self.storage.mempool().pending_txx()
+    }
+
+    async fn get_mempool_tx(&self, txhash: TxHash) -> Option<Transaction> {
+        log::trace!("handling get_mempool_tx({})", txhash);
+        self.storage.mempool().pending_tx(txhash)
+    }
+
+    async fn get_mempool_stats(&self) -> MempoolStats {
+        log::trace!("handling get_mempool_stats()");
+        self.storage.mempool().stats()
+    }
+}
+
+/// The leaves of a block's TIP-908 dense Merkle tree of transactions, in order.
+fn dense_leaves(block: &Block) -> Vec<Vec<u8>> {
+    let mut leaves: Vec<Vec<u8>> = block
+        .transactions
+        .iter()
+        .map(|tx| {
+            let mut leaf = tx.hash_nosigs().0.to_vec();
+            leaf.extend_from_slice(&tx.stdcode().hash().0);
+            leaf
+        })
+        .collect();
+    leaves.sort_unstable();
+    leaves
+}
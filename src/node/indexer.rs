@@ -40,11 +40,13 @@ impl WrappedIndexer {
 
 async fn indexer_loop(storage: Storage, client: Client) {
     loop {
-        let trusted_height = storage.highest_state().await;
-        client.trust(Checkpoint {
-            height: trusted_height.header().height,
-            header_hash: trusted_height.header().hash(),
-        });
+        match storage.highest_state().await {
+            Ok(trusted_height) => client.trust(Checkpoint {
+                height: trusted_height.header().height,
+                header_hash: trusted_height.header().hash(),
+            }),
+            Err(err) => log::warn!("indexer cannot read the highest state: {err}"),
+        }
         smol::Timer::after(Duration::from_secs(1)).await;
     }
 }
@@ -1,6 +1,6 @@
 use crate::{
     args::StakerConfig,
-    storage::{MeshaCas, Storage},
+    storage::{ForestStore, Storage},
 };
 
 use anyhow::Context;
@@ -109,10 +109,10 @@ async fn network_task_inner(storage: Storage, cfg: StakerConfig) -> anyhow::Resu
         .context("cannot start listen")?;
     // TODO better time calcs
     loop {
-        let base_state = storage.highest_state().await;
+        let base_state = storage.highest_state().await?;
         let next_height: BlockHeight = base_state.header().height + BlockHeight(1);
         let skip_round = async {
-            storage.get_state_or_wait(next_height).await;
+            storage.get_state_or_wait(next_height).await?;
             log::warn!("skipping consensus for {next_height} since we already got it");
             anyhow::Ok(())
         };
@@ -131,7 +131,7 @@ async fn network_task_inner(storage: Storage, cfg: StakerConfig) -> anyhow::Resu
         let log_key = format!("{next_height}/{}", cfg.listen);
 
         let decide_round = async {
-            let proposed_state = storage.mempool().to_state();
+            let proposed_state = storage.mempool().proposal_state();
             let sealed_proposed_state = proposed_state.clone().seal(None);
             if sealed_proposed_state.header().height != next_height {
                 log::warn!("mempool not at the right height, trying again");
@@ -204,8 +204,7 @@ async fn network_task_inner(storage: Storage, cfg: StakerConfig) -> anyhow::Resu
                 };
                 loop {
                     if let Some(result) = get_proof() {
-                        let cproof: ConsensusProof =
-                            result.clone().into_iter().map(|(k, v)| (k, v)).collect();
+                        let cproof: ConsensusProof = result.clone().into_iter().collect();
                         if let Err(err) = storage.apply_block(decision.clone(), cproof).await {
                             log::error!("cannot commit newly decided block: {:?}", err)
                         }
@@ -255,7 +254,7 @@ async fn network_task_inner(storage: Storage, cfg: StakerConfig) -> anyhow::Resu
 }
 
 struct StakerInner {
-    base_state: SealedState<MeshaCas>,
+    base_state: SealedState<ForestStore>,
     my_proposal: Block,
     nonce: u128,
     my_sk: Ed25519SK,
@@ -383,15 +382,11 @@ impl StakerNetProtocol for StakerNetProtocolImpl {
         let (send_resp, recv_resp) = async_oneshot::oneshot();
         let _ = self.send_diff_req.try_send((nonce, summary, send_resp));
 
-        if let Ok(val) = recv_resp.await {
-            val
-        } else {
-            vec![]
-        }
+        recv_resp.await.unwrap_or_default()
     }
 
     async fn get_sigs(&self, height: BlockHeight) -> HashMap<Ed25519PK, Bytes> {
-        if let Some(val) = self.storage.get_consensus(height).await {
+        if let Ok(val) = self.storage.get_consensus(height).await {
             val.into_iter().collect()
         } else {
             self.sig_gather
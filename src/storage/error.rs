@@ -0,0 +1,89 @@
+use std::fmt::Display;
+
+use melstructs::BlockHeight;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::autoretry::Transient;
+
+/// Errors returned by [Storage](super::Storage)'s accessors.
+#[derive(thiserror::Error, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "detail", rename_all = "snake_case")]
+pub enum StorageError {
+    /// The requested item doesn't exist, or doesn't exist yet.
+    #[error("not found: {0}")]
+    NotFound(String),
+    /// The requested height has been pruned away.
+    #[error("height {0} has been pruned")]
+    Pruned(BlockHeight),
+    /// Stored data fails to decode, or disagrees with itself.
+    #[error("corrupt database: {0}")]
+    Corrupt(String),
+    /// The underlying database or filesystem failed.
+    #[error("I/O error: {0}")]
+    Io(String),
+    /// The database stayed locked through every retry.
+    #[error("database busy: {0}")]
+    Busy(String),
+    /// The request itself can never be answered.
+    #[error("bad request: {0}")]
+    BadRequest(String),
+}
+
+impl StorageError {
+    // alternate formatting keeps the whole context chain of an anyhow error
+    pub(super) fn corrupt(err: impl Display) -> Self {
+        Self::Corrupt(format!("{err:#}"))
+    }
+
+    pub(super) fn io(err: impl Display) -> Self {
+        Self::Io(format!("{err:#}"))
+    }
+
+    pub(super) fn bad_request(err: impl Display) -> Self {
+        Self::BadRequest(format!("{err:#}"))
+    }
+}
+
+impl Transient for StorageError {
+    fn is_transient(&self) -> bool {
+        matches!(self, Self::Busy(_))
+    }
+}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(err: rusqlite::Error) -> Self {
+        use rusqlite::ErrorCode;
+        match err.sqlite_error_code() {
+            Some(ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked) => {
+                Self::Busy(err.to_string())
+            }
+            Some(ErrorCode::DatabaseCorrupt | ErrorCode::NotADatabase) => {
+                Self::Corrupt(err.to_string())
+            }
+            Some(_) => Self::Io(err.to_string()),
+            // everything else is about the values read out, rather than sqlite itself
+            None => Self::Corrupt(err.to_string()),
+        }
+    }
+}
+
+/// Decodes a stdcode-encoded blob read from the database.
+pub(super) fn decode<T: DeserializeOwned>(blob: &[u8]) -> Result<T, StorageError> {
+    stdcode::deserialize(blob).map_err(StorageError::corrupt)
+}
+
+/// Extension methods for results of [Storage](super::Storage) accessors.
+pub trait StorageResultExt<T> {
+    /// Turns [StorageError::NotFound] into `Ok(None)`, keeping every other error.
+    fn optional(self) -> Result<Option<T>, StorageError>;
+}
+
+impl<T> StorageResultExt<T> for Result<T, StorageError> {
+    fn optional(self) -> Result<Option<T>, StorageError> {
+        match self {
+            Ok(val) => Ok(Some(val)),
+            Err(StorageError::NotFound(_)) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
@@ -1,74 +1,606 @@
-use crate::storage::MeshaCas;
+use crate::storage::ForestStore;
 
-use std::collections::HashSet;
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BTreeSet, BinaryHeap, HashMap, HashSet},
+};
 
 use melstf::{SealedState, StateError, UnsealedState};
-use melstructs::{Transaction, TxHash};
+use melstructs::{BlockHeight, CoinValue, Transaction, TxHash};
 use melvm::covenant_weight_from_bytes;
+use serde::{Deserialize, Serialize};
 
-const WEIGHT_LIMIT: u128 = 10_000_000;
+/// Most weight that a proposed block may carry.
+pub const WEIGHT_LIMIT: u128 = 10_000_000;
+
+/// Most weight of pending transactions that the mempool holds.
+const POOL_WEIGHT_LIMIT: u128 = 4 * WEIGHT_LIMIT;
+
+/// Total weight of recently seen transactions that the mempool remembers.
+const RECENT_WEIGHT_LIMIT: u64 = POOL_WEIGHT_LIMIT as u64;
 
 /// Mempool encapsulates a "mempool" --- a provisional state that is used to form new blocks by stakers, or provisionally validate transactions by replicas.
 pub struct Mempool {
-    provisional_state: UnsealedState<MeshaCas>,
-    last_rebase: UnsealedState<MeshaCas>,
-    txx_in_state: HashSet<TxHash>,
-    next_weight: u128, // seen: LruCache<TxHash, ()>,
+    provisional_state: UnsealedState<ForestStore>,
+    last_rebase: UnsealedState<ForestStore>,
+    /// Every transaction applied to the provisional state since the last rebase.
+    pending: HashMap<TxHash, PendingTx>,
+    /// The pending transactions by eviction score, cheapest first, and newest first among equals.
+    by_score: BTreeSet<(FeeRate, Reverse<u64>, TxHash)>,
+    /// Total weight of the pending transactions.
+    next_weight: u128,
+    next_seq: u64,
+    /// Height of the sealed state that the pending transactions apply on top of.
+    base_height: BlockHeight,
+    /// Recently accepted transactions, even ones no longer pending.
+    recent: moka::sync::Cache<TxHash, Transaction>,
+}
+
+/// A pending transaction, as listed by [Mempool::pending_txx].
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct PendingTxSummary {
+    pub txhash: TxHash,
+    pub fee: CoinValue,
+    pub weight: u128,
+}
+
+/// What a [Mempool] holds, as reported by [Mempool::stats].
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct MempoolStats {
+    /// Number of pending transactions.
+    pub count: u64,
+    /// Total weight of the pending transactions.
+    pub weight: u128,
+    /// Most weight that a block may carry.
+    pub weight_limit: u128,
+    /// Most weight that the mempool holds before it starts evicting.
+    pub pool_weight_limit: u128,
+    /// Height of the state that the pending transactions apply on top of.
+    pub base_height: BlockHeight,
+}
+
+/// A transaction waiting in the mempool.
+#[derive(Clone, Debug)]
+struct PendingTx {
+    tx: Transaction,
+    weight: u128,
+    /// Order of arrival, which puts parents before their children.
+    seq: u64,
+    /// Pending transactions whose outputs this one spends.
+    parents: Vec<TxHash>,
+    /// Pending transactions that spend this one's outputs.
+    children: Vec<TxHash>,
+    /// What evicting it and its descendants would save per unit of weight, or its own fee rate if that's higher.
+    score: FeeRate,
+}
+
+/// A fee per unit of weight, compared exactly rather than rounded.
+#[derive(Clone, Copy, Debug, Default)]
+struct FeeRate {
+    fee: u128,
+    weight: u128,
+}
+
+impl FeeRate {
+    /// The combined fee rate of some transactions.
+    fn of<'a>(txx: impl IntoIterator<Item = &'a PendingTx>) -> Self {
+        txx.into_iter().fold(Self::default(), |acc, ptx| Self {
+            fee: acc.fee + ptx.tx.fee.0,
+            weight: acc.weight + ptx.weight,
+        })
+    }
+}
+
+impl PartialEq for FeeRate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for FeeRate {}
+
+impl PartialOrd for FeeRate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for FeeRate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.fee
+            .saturating_mul(other.weight)
+            .cmp(&other.fee.saturating_mul(self.weight))
+    }
 }
 
 impl Mempool {
     /// Create sa new mempool based on a provisional state.
-    pub fn new(state: UnsealedState<MeshaCas>) -> Self {
+    pub fn new(state: UnsealedState<ForestStore>) -> Self {
         Self {
             provisional_state: state.clone(),
             last_rebase: state,
-            txx_in_state: Default::default(),
+            pending: Default::default(),
+            by_score: Default::default(),
             next_weight: 0,
-            // seen: LruCache::new(10000),
+            next_seq: 0,
+            base_height: BlockHeight(0),
+            recent: moka::sync::Cache::builder()
+                .weigher(|_, tx: &Transaction| {
+                    tx.weight(covenant_weight_from_bytes)
+                        .try_into()
+                        .unwrap_or(u32::MAX)
+                })
+                .max_capacity(RECENT_WEIGHT_LIMIT)
+                .build(),
         }
     }
     /// Creates a State based on the present state of the mempool.
-    pub fn to_state(&self) -> UnsealedState<MeshaCas> {
+    pub fn to_state(&self) -> UnsealedState<ForestStore> {
         self.provisional_state.clone()
     }
 
+    /// Creates the state a staker should propose, with the best-paying pending transactions.
+    pub fn proposal_state(&self) -> UnsealedState<ForestStore> {
+        if self.next_weight <= WEIGHT_LIMIT {
+            return self.provisional_state.clone();
+        }
+        let picked = self.proposal_txx();
+        let (state, _) = self.replay(
+            self.in_order()
+                .filter(|ptx| picked.contains(&ptx.tx.hash_nosigs())),
+        );
+        state
+    }
+
     /// Tries to add a transaction to the mempool.
     pub fn apply_transaction(&mut self, tx: &Transaction) -> anyhow::Result<()> {
-        if self.next_weight < WEIGHT_LIMIT {
-            if !self.txx_in_state.insert(tx.hash_nosigs()) {
-                return Err(StateError::DuplicateTx.into());
-            }
-            self.provisional_state.apply_tx(tx)?;
-            self.next_weight += tx.weight(covenant_weight_from_bytes);
-            // self.seen.put(tx.hash_nosigs(), ());
-            Ok(())
+        let txhash = tx.hash_nosigs();
+        if self.pending.contains_key(&txhash) {
+            return Err(StateError::DuplicateTx.into());
+        }
+        let weight = tx.weight(covenant_weight_from_bytes);
+        if weight > WEIGHT_LIMIT {
+            anyhow::bail!("transaction is heavier than a whole block");
+        }
+        let parents: Vec<TxHash> = tx
+            .inputs
+            .iter()
+            .map(|input| input.txhash)
+            .filter(|parent| self.pending.contains_key(parent))
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+
+        let victims = if self.next_weight + weight > POOL_WEIGHT_LIMIT {
+            let keep = self.ancestors_from(&parents);
+            self.victims(
+                FeeRate {
+                    fee: tx.fee.0,
+                    weight,
+                },
+                keep,
+            )?
+        } else {
+            HashSet::new()
+        };
+        let (mut state, dropped) = if victims.is_empty() {
+            (self.provisional_state.clone(), vec![])
         } else {
-            anyhow::bail!("mempool is full, try again later")
+            self.replay(
+                self.in_order()
+                    .filter(|ptx| !victims.contains(&ptx.tx.hash_nosigs())),
+            )
+        };
+        state.apply_tx(tx)?;
+
+        for evicted in victims
+            .iter()
+            .chain(dropped.iter().map(|(txhash, _)| txhash))
+        {
+            self.remove_pending(*evicted);
+        }
+        if !victims.is_empty() {
+            log::debug!(
+                "evicted {} mempool txx to make room for {}",
+                victims.len(),
+                txhash
+            );
         }
+        self.provisional_state = state;
+        self.recent.insert(txhash, tx.clone());
+        self.insert_pending(
+            txhash,
+            PendingTx {
+                tx: tx.clone(),
+                weight,
+                seq: self.next_seq,
+                parents,
+                children: vec![],
+                score: FeeRate::default(),
+            },
+        );
+        self.next_seq += 1;
+        Ok(())
     }
 
-    /// Forcibly replaces the internal state of the mempool with the given state.
-    pub fn rebase(&mut self, state: SealedState<MeshaCas>) {
-        let current_sealed = self.provisional_state.clone().seal(None);
+    /// Moves the mempool onto a new state, usually one with a freshly applied block.
+    pub fn rebase(&mut self, state: SealedState<ForestStore>) -> Vec<(TxHash, StateError)> {
         log::trace!(
-            "forcibly rebasing mempool {} => {}",
-            current_sealed.header().height,
-            state.header().height
+            "rebasing mempool onto {} with {} pending txx",
+            state.header().height,
+            self.pending.len()
         );
-        if !current_sealed.is_empty() {
-            let transactions = current_sealed.to_block().transactions;
-            log::warn!("*** THROWING AWAY {} MEMPOOL TXX ***", transactions.len());
+        for tx in state.to_block().transactions {
+            self.remove_pending(tx.hash_nosigs());
+        }
+        self.base_height = state.header().height;
+        self.last_rebase = state.next_unsealed();
+        let (next_state, dropped) = self.replay(self.in_order());
+        for (txhash, err) in dropped.iter() {
+            log::debug!("dropping mempool tx {}: {:?}", txhash, err);
+            if let Some(ptx) = self.remove_pending(*txhash) {
+                // a competing block may still include it
+                self.recent.insert(*txhash, ptx.tx);
+            }
+        }
+        if !dropped.is_empty() {
+            log::warn!(
+                "dropped {} mempool txx that no longer apply, kept {}",
+                dropped.len(),
+                self.pending.len()
+            );
+        }
+        self.provisional_state = next_state;
+        dropped
+    }
+
+    /// Summarizes every pending transaction, in order of arrival.
+    pub fn pending_txx(&self) -> Vec<PendingTxSummary> {
+        self.in_order()
+            .map(|ptx| PendingTxSummary {
+                txhash: ptx.tx.hash_nosigs(),
+                fee: ptx.tx.fee,
+                weight: ptx.weight,
+            })
+            .collect()
+    }
+
+    /// Gets a pending transaction by its hash.
+    pub fn pending_tx(&self, txhash: TxHash) -> Option<Transaction> {
+        self.pending.get(&txhash).map(|ptx| ptx.tx.clone())
+    }
+
+    /// Reports how full the mempool is.
+    pub fn stats(&self) -> MempoolStats {
+        MempoolStats {
+            count: self.pending.len() as u64,
+            weight: self.next_weight,
+            weight_limit: WEIGHT_LIMIT,
+            pool_weight_limit: POOL_WEIGHT_LIMIT,
+            base_height: self.base_height,
         }
+    }
 
-        let next_state = state.next_unsealed();
-        self.provisional_state = next_state.clone();
-        self.last_rebase = next_state;
-        self.txx_in_state.clear();
-        self.next_weight = 0;
+    /// Looks up a transaction that the mempool holds or has recently seen.
+    pub fn lookup_recent_tx(&self, hash: TxHash) -> Option<Transaction> {
+        self.pending_tx(hash).or_else(|| self.recent.get(&hash))
+    }
+
+    /// Picks the cheapest pending transactions to evict for the given fee rate, sparing `keep`.
+    fn victims(&self, rate: FeeRate, keep: HashSet<TxHash>) -> anyhow::Result<HashSet<TxHash>> {
+        let mut victims = HashSet::new();
+        let mut freed = 0;
+        let mut candidates = self
+            .by_score
+            .iter()
+            .filter(|(_, _, txhash)| !keep.contains(txhash));
+        while self.next_weight - freed + rate.weight > POOL_WEIGHT_LIMIT {
+            let Some((score, _, txhash)) = candidates.next() else {
+                anyhow::bail!("mempool is full, try again later");
+            };
+            if *score >= rate {
+                anyhow::bail!(
+                    "mempool is full, and the transaction doesn't pay enough per unit of weight to evict anything"
+                );
+            }
+            if victims.contains(txhash) {
+                continue;
+            }
+            for txhash in self.descendants(*txhash) {
+                if victims.insert(txhash) {
+                    freed += self.pending[&txhash].weight;
+                }
+            }
+        }
+        Ok(victims)
     }
 
-    /// Lookups a recent transaction.
-    pub fn lookup_recent_tx(&self, _hash: TxHash) -> Option<Transaction> {
-        None
+    /// Picks the pending transactions for [Mempool::proposal_state].
+    fn proposal_txx(&self) -> HashSet<TxHash> {
+        let ancestors: HashMap<TxHash, HashSet<TxHash>> = self
+            .pending
+            .keys()
+            .map(|txhash| (*txhash, self.ancestors(*txhash)))
+            .collect();
+        // the fee rate of each transaction together with its ancestors that aren't picked yet
+        let mut packages: HashMap<TxHash, FeeRate> = ancestors
+            .iter()
+            .map(|(txhash, ancs)| {
+                let package = ancs.iter().chain([txhash]).map(|h| &self.pending[h]);
+                (*txhash, FeeRate::of(package))
+            })
+            .collect();
+        let mut heap: BinaryHeap<(FeeRate, Reverse<u64>, TxHash)> = packages
+            .iter()
+            .map(|(txhash, rate)| (*rate, Reverse(self.pending[txhash].seq), *txhash))
+            .collect();
+        let mut picked: HashSet<TxHash> = HashSet::new();
+        let mut weight = 0;
+        while let Some((rate, _, txhash)) = heap.pop() {
+            let current = packages[&txhash];
+            // entries go stale once an ancestor is picked, and are pushed again with the new rate
+            if picked.contains(&txhash) || (current.fee, current.weight) != (rate.fee, rate.weight)
+            {
+                continue;
+            }
+            if weight + rate.weight > WEIGHT_LIMIT {
+                continue;
+            }
+            weight += rate.weight;
+            let package: Vec<TxHash> = ancestors[&txhash]
+                .iter()
+                .copied()
+                .chain([txhash])
+                .filter(|h| !picked.contains(h))
+                .collect();
+            picked.extend(package.iter().copied());
+            let mut changed = HashSet::new();
+            for member in package {
+                let ptx = &self.pending[&member];
+                for desc in self.descendants(member) {
+                    if !picked.contains(&desc) {
+                        let desc_rate = packages.get_mut(&desc).expect("no package");
+                        desc_rate.fee -= ptx.tx.fee.0;
+                        desc_rate.weight -= ptx.weight;
+                        changed.insert(desc);
+                    }
+                }
+            }
+            heap.extend(
+                changed
+                    .into_iter()
+                    .map(|desc| (packages[&desc], Reverse(self.pending[&desc].seq), desc)),
+            );
+        }
+        picked
+    }
+
+    /// Replays pending transactions on the last rebase, returning those that no longer apply.
+    fn replay<'a>(
+        &self,
+        txx: impl Iterator<Item = &'a PendingTx>,
+    ) -> (UnsealedState<ForestStore>, Vec<(TxHash, StateError)>) {
+        let txx: Vec<Transaction> = txx.map(|ptx| ptx.tx.clone()).collect();
+        let mut state = self.last_rebase.clone();
+        // all at once is much faster, and almost always works
+        if state.apply_tx_batch(&txx).is_ok() {
+            return (state, vec![]);
+        }
+        let mut dropped = vec![];
+        for tx in txx {
+            if let Err(err) = state.apply_tx(&tx) {
+                dropped.push((tx.hash_nosigs(), err));
+            }
+        }
+        (state, dropped)
+    }
+
+    /// The pending transactions, in order of arrival.
+    fn in_order(&self) -> impl Iterator<Item = &PendingTx> {
+        let mut txx: Vec<&PendingTx> = self.pending.values().collect();
+        txx.sort_unstable_by_key(|ptx| ptx.seq);
+        txx.into_iter()
+    }
+
+    /// Adds a transaction to the pending set, linking it to its pending parents.
+    fn insert_pending(&mut self, txhash: TxHash, mut ptx: PendingTx) {
+        ptx.parents
+            .retain(|parent| self.pending.contains_key(parent));
+        for parent in ptx.parents.iter() {
+            if let Some(parent) = self.pending.get_mut(parent) {
+                parent.children.push(txhash);
+            }
+        }
+        ptx.score = FeeRate::of([&ptx]);
+        self.by_score.insert((ptx.score, Reverse(ptx.seq), txhash));
+        self.next_weight += ptx.weight;
+        self.pending.insert(txhash, ptx);
+        // every ancestor now has one more descendant that evicting it would save
+        for ancestor in self.ancestors(txhash) {
+            self.rescore(ancestor);
+        }
+    }
+
+    /// Takes a transaction out of the pending set, unlinking it from its pending parents and children.
+    fn remove_pending(&mut self, txhash: TxHash) -> Option<PendingTx> {
+        let ptx = self.pending.remove(&txhash)?;
+        self.by_score.remove(&(ptx.score, Reverse(ptx.seq), txhash));
+        self.next_weight -= ptx.weight;
+        for parent in ptx.parents.iter() {
+            if let Some(parent) = self.pending.get_mut(parent) {
+                parent.children.retain(|child| *child != txhash);
+            }
+        }
+        for child in ptx.children.iter() {
+            if let Some(child) = self.pending.get_mut(child) {
+                child.parents.retain(|parent| *parent != txhash);
+            }
+        }
+        for ancestor in self.ancestors_from(&ptx.parents) {
+            self.rescore(ancestor);
+        }
+        Some(ptx)
+    }
+
+    /// Recomputes the eviction score of a pending transaction after its descendants change.
+    fn rescore(&mut self, txhash: TxHash) {
+        let family = self.descendants(txhash);
+        let ptx = &self.pending[&txhash];
+        let score = FeeRate::of([ptx]).max(FeeRate::of(family.iter().map(|h| &self.pending[h])));
+        let ptx = self.pending.get_mut(&txhash).expect("not pending");
+        self.by_score.remove(&(ptx.score, Reverse(ptx.seq), txhash));
+        ptx.score = score;
+        self.by_score.insert((score, Reverse(ptx.seq), txhash));
+    }
+
+    /// A pending transaction and everything pending that depends on it.
+    fn descendants(&self, txhash: TxHash) -> Vec<TxHash> {
+        let mut seen = HashSet::new();
+        let mut stack = vec![txhash];
+        while let Some(txhash) = stack.pop() {
+            if seen.insert(txhash) {
+                stack.extend(self.pending[&txhash].children.iter().copied());
+            }
+        }
+        seen.into_iter().collect()
+    }
+
+    /// Every pending ancestor of a pending transaction.
+    fn ancestors(&self, txhash: TxHash) -> HashSet<TxHash> {
+        self.ancestors_from(&self.pending[&txhash].parents)
+    }
+
+    /// Some pending transactions and all their pending ancestors.
+    fn ancestors_from(&self, parents: &[TxHash]) -> HashSet<TxHash> {
+        let mut seen = HashSet::new();
+        let mut stack = parents.to_vec();
+        while let Some(txhash) = stack.pop() {
+            if seen.insert(txhash) {
+                stack.extend(self.pending[&txhash].parents.iter().copied());
+            }
+        }
+        seen
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::ForestBackend;
+    use melstf::GenesisConfig;
+    use melstructs::CoinID;
+
+    fn mempool() -> Mempool {
+        let forest = ForestBackend::Memory
+            .open(std::path::Path::new("."))
+            .unwrap();
+        Mempool::new(GenesisConfig::std_testnet().realize(&novasmt::Database::new(forest)))
+    }
+
+    /// Adds a transaction spending `parents` straight to the pending set, without applying it.
+    fn add(pool: &mut Mempool, fee: u128, weight: u128, parents: &[TxHash]) -> TxHash {
+        let tx = Transaction {
+            inputs: parents
+                .iter()
+                .map(|parent| CoinID::new(*parent, 0))
+                .collect(),
+            fee: CoinValue(fee),
+            data: pool.next_seq.to_be_bytes().to_vec().into(),
+            ..Default::default()
+        };
+        let txhash = tx.hash_nosigs();
+        pool.insert_pending(
+            txhash,
+            PendingTx {
+                tx,
+                weight,
+                seq: pool.next_seq,
+                parents: parents.to_vec(),
+                children: vec![],
+                score: FeeRate::default(),
+            },
+        );
+        pool.next_seq += 1;
+        txhash
+    }
+
+    fn rate(fee: u128) -> FeeRate {
+        FeeRate {
+            fee,
+            weight: WEIGHT_LIMIT,
+        }
+    }
+
+    #[test]
+    fn children_are_evicted_with_their_parents() {
+        let mut pool = mempool();
+        let parent = add(&mut pool, 0, WEIGHT_LIMIT, &[]);
+        let child = add(&mut pool, 20, WEIGHT_LIMIT, &[parent]);
+        add(&mut pool, 1000, WEIGHT_LIMIT, &[]);
+        add(&mut pool, 1000, WEIGHT_LIMIT, &[]);
+        let victims = pool.victims(rate(100), HashSet::new()).unwrap();
+        assert_eq!(victims, [parent, child].into_iter().collect());
+
+        for txhash in victims {
+            pool.remove_pending(txhash);
+        }
+        assert_eq!(pool.by_score.len(), 2);
+        assert_eq!(pool.next_weight, 2 * WEIGHT_LIMIT);
+    }
+
+    #[test]
+    fn well_paying_children_protect_their_parents() {
+        let mut pool = mempool();
+        let parent = add(&mut pool, 0, WEIGHT_LIMIT, &[]);
+        add(&mut pool, 3000, WEIGHT_LIMIT, &[parent]);
+        add(&mut pool, 1000, WEIGHT_LIMIT, &[]);
+        let cheapest = add(&mut pool, 1000, WEIGHT_LIMIT, &[]);
+        let victims = pool.victims(rate(2000), HashSet::new()).unwrap();
+        assert_eq!(victims, [cheapest].into_iter().collect());
+    }
+
+    #[test]
+    fn keep_is_spared() {
+        let mut pool = mempool();
+        let grandparent = add(&mut pool, 0, WEIGHT_LIMIT, &[]);
+        let parent = add(&mut pool, 0, WEIGHT_LIMIT, &[grandparent]);
+        add(&mut pool, 1000, WEIGHT_LIMIT, &[]);
+        let newest = add(&mut pool, 1000, WEIGHT_LIMIT, &[]);
+        let keep = pool.ancestors_from(&[parent]);
+        assert_eq!(keep, [grandparent, parent].into_iter().collect());
+
+        assert!(pool.victims(rate(100), keep.clone()).is_err());
+        let victims = pool.victims(rate(2000), keep).unwrap();
+        assert_eq!(victims, [newest].into_iter().collect());
+    }
+
+    #[test]
+    fn ties_evict_the_newest_and_propose_the_oldest() {
+        let mut pool = mempool();
+        let oldest = add(&mut pool, 100, WEIGHT_LIMIT, &[]);
+        add(&mut pool, 100, WEIGHT_LIMIT, &[]);
+        add(&mut pool, 100, WEIGHT_LIMIT, &[]);
+        let newest = add(&mut pool, 100, WEIGHT_LIMIT, &[]);
+        let victims = pool.victims(rate(200), HashSet::new()).unwrap();
+        assert_eq!(victims, [newest].into_iter().collect());
+        assert_eq!(pool.proposal_txx(), [oldest].into_iter().collect());
+    }
+
+    #[test]
+    fn tied_packages_are_proposed_oldest_first() {
+        // a parent and child paying 200 together, against a single transaction paying 200 for the same weight
+        let half = WEIGHT_LIMIT * 3 / 5;
+        let mut pool = mempool();
+        let parent = add(&mut pool, 0, half / 2, &[]);
+        let child = add(&mut pool, 200, half / 2, &[parent]);
+        add(&mut pool, 200, half, &[]);
+        assert_eq!(pool.proposal_txx(), [parent, child].into_iter().collect());
+
+        let mut pool = mempool();
+        let single = add(&mut pool, 200, half, &[]);
+        let parent = add(&mut pool, 0, half / 2, &[]);
+        add(&mut pool, 200, half / 2, &[parent]);
+        // the parent alone still fits in what's left
+        assert_eq!(pool.proposal_txx(), [single, parent].into_iter().collect());
     }
 }
@@ -0,0 +1,195 @@
+use std::{collections::HashMap, hash::Hasher, io::Write, path::Path};
+
+use memmap::{Mmap, MmapOptions};
+use parking_lot::RwLock;
+use siphasher::sip::SipHasher13;
+
+const RECORD_KIND_DATA: u32 = 0x00;
+const RECORD_KIND_HAMI: u32 = 0x01;
+const RECORD_KIND_HAMR: u32 = 0x02;
+
+/// Records start after the reserved region at the start of the file.
+const RESERVED: u64 = 4096;
+
+/// How far back from the end of the file to look for a complete root, as far as meshanina itself looks.
+const ROOT_SEARCH: u64 = 100_000_000;
+
+/// A read-only view of a meshanina database that a node may still be appending to.
+///
+/// Inserts are kept in memory and never reach the file.
+pub(super) struct MeshaReader {
+    file: std::fs::File,
+    mmap: Mmap,
+    divider: u128,
+    /// Offset of the newest root known to be complete.
+    root: RwLock<u64>,
+    overlay: RwLock<HashMap<[u8; 32], Vec<u8>>>,
+}
+
+/// A record, borrowed from the mmap.
+enum Record<'a> {
+    Data([u8; 32], &'a [u8]),
+    Hamt(bool, u64, &'a [u8]),
+}
+
+impl MeshaReader {
+    /// Opens an existing meshanina database without locking or writing to it.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = std::fs::File::open(path)?;
+        if file.metadata()?.len() < RESERVED {
+            anyhow::bail!("{} is not a meshanina database", path.display());
+        }
+        // like meshanina itself, map far past the end, so that what gets appended later is visible too.
+        // SAFETY: meshanina only ever appends, and every read is bounded by a length the file has already reached.
+        let mmap = unsafe { MmapOptions::new().len(1 << 39).map(&file)? };
+        let divider = u128::from_le_bytes(mmap[10..26].try_into().unwrap());
+        let reader = Self {
+            file,
+            mmap,
+            divider,
+            root: RwLock::new(0),
+            overlay: Default::default(),
+        };
+        reader.refresh()?;
+        Ok(reader)
+    }
+
+    /// Looks up a key, seeing whatever the writer has flushed since the last lookup.
+    pub fn get(&self, key: [u8; 32]) -> Option<Vec<u8>> {
+        if let Some(val) = self.overlay.read().get(&key) {
+            return Some(val.clone());
+        }
+        let root = *self.root.read();
+        if let Some(val) = self.lookup(root, key) {
+            return Some(val);
+        }
+        match self.refresh() {
+            Ok(newer) if newer != root => self.lookup(newer, key),
+            Ok(_) => None,
+            Err(err) => {
+                log::warn!("cannot refresh read-only merkle.db: {:?}", err);
+                None
+            }
+        }
+    }
+
+    /// Keeps a key-value pair in memory.
+    pub fn insert(&self, key: [u8; 32], value: &[u8]) {
+        self.overlay.write().insert(key, value.to_vec());
+    }
+
+    /// Copies the file up to the end of the newest complete root.
+    pub fn copy_to(&self, path: &Path) -> anyhow::Result<()> {
+        let root = self.refresh()?;
+        let end = if root == 0 {
+            RESERVED
+        } else {
+            let length =
+                u32::from_le_bytes(self.mmap[root as usize + 28..][..4].try_into().unwrap());
+            root + 32 + length as u64
+        };
+        let mut out = std::fs::File::create(path)?;
+        out.write_all(&self.mmap[..end as usize])?;
+        out.sync_all()?;
+        Ok(())
+    }
+
+    /// Finds the newest complete root, searching backwards from the end of the file.
+    fn refresh(&self) -> anyhow::Result<u64> {
+        let len = self.file.metadata()?.len();
+        let divider = self.divider.to_le_bytes();
+        let floor = len.saturating_sub(ROOT_SEARCH).max(RESERVED);
+        let mut posn = len.saturating_sub(16);
+        while posn >= floor {
+            if self.mmap[posn as usize..][..16] == divider {
+                if let Some(Record::Hamt(true, _, _)) = self.record(posn, len) {
+                    *self.root.write() = posn;
+                    return Ok(posn);
+                }
+            }
+            posn -= 1;
+        }
+        if len > RESERVED {
+            anyhow::bail!(
+                "no complete root in the last {} bytes of merkle.db",
+                ROOT_SEARCH
+            );
+        }
+        Ok(0)
+    }
+
+    fn lookup(&self, root: u64, key: [u8; 32]) -> Option<Vec<u8>> {
+        if root == 0 {
+            return None;
+        }
+        let mut ikey = u128::from_le_bytes(key[..16].try_into().unwrap());
+        // the root was checked to fit in the file when it was found
+        let mut record = self.record(root, u64::MAX)?;
+        loop {
+            match record {
+                Record::Data(k, v) => {
+                    return if k == key {
+                        lz4_flex::decompress_size_prepended(v).ok()
+                    } else {
+                        None
+                    }
+                }
+                Record::Hamt(_, bitmap, ptrs) => {
+                    let hindex = (ikey & 0b111111) as u32;
+                    if (bitmap >> hindex) & 1 == 0 {
+                        return None;
+                    }
+                    let idx = (bitmap & ((1u64 << hindex) - 1)).count_ones() as usize;
+                    let ptr = u64::from_le_bytes(ptrs[idx * 8..][..8].try_into().unwrap());
+                    // everything reachable from a complete root was written before it
+                    if ptr >= root {
+                        return None;
+                    }
+                    record = self.record(ptr, root)?;
+                    ikey >>= 6;
+                }
+            }
+        }
+    }
+
+    /// Parses the record at an offset, returning `None` if it's malformed or runs past `len`.
+    fn record(&self, posn: u64, len: u64) -> Option<Record<'_>> {
+        if posn.checked_add(32)? > len {
+            return None;
+        }
+        let b = &self.mmap[posn as usize..];
+        if u128::from_le_bytes(b[..16].try_into().unwrap()) != self.divider {
+            return None;
+        }
+        let b = &b[16..];
+        let checksum = u64::from_le_bytes(b[..8].try_into().unwrap());
+        let kind = u32::from_le_bytes(b[8..12].try_into().unwrap());
+        let length = u32::from_le_bytes(b[12..16].try_into().unwrap()) as usize;
+        if posn + 32 + length as u64 > len.min(self.mmap.len() as u64) {
+            return None;
+        }
+        let payload = &b[16..][..length];
+        match kind {
+            RECORD_KIND_DATA if length >= 32 => Some(Record::Data(
+                payload[..32].try_into().unwrap(),
+                &payload[32..],
+            )),
+            RECORD_KIND_HAMI | RECORD_KIND_HAMR if length >= 8 => {
+                if kind == RECORD_KIND_HAMR {
+                    let mut h = SipHasher13::new_with_key(&self.divider.to_le_bytes());
+                    h.write(&b[8..][..length + 8]);
+                    if h.finish() != checksum {
+                        return None;
+                    }
+                }
+                let bitmap = u64::from_le_bytes(payload[..8].try_into().unwrap());
+                let ptrs = &payload[8..];
+                if bitmap.count_ones() as usize * 8 != ptrs.len() {
+                    return None;
+                }
+                Some(Record::Hamt(kind == RECORD_KIND_HAMR, bitmap, ptrs))
+            }
+            _ => None,
+        }
+    }
+}
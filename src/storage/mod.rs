@@ -1,8 +1,21 @@
+mod error;
 mod mempool;
+mod mesha_reader;
+mod readonly;
+mod schema;
 mod smt;
+mod snapshot;
+mod sqlite_cas;
+mod verify;
 
 #[allow(clippy::module_inception)]
 mod storage;
 
+pub use error::*;
+pub use mempool::*;
+pub use readonly::*;
+pub use schema::*;
 pub use smt::*;
+pub use snapshot::*;
 pub use storage::*;
+pub use verify::*;
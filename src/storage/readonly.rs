@@ -0,0 +1,61 @@
+use std::path::PathBuf;
+
+use melstf::SealedState;
+use melstructs::{Block, BlockHeight, ConsensusProof};
+
+use super::{ForestStore, Storage, StorageError, VerifyReport};
+
+/// A read-only handle to a node's storage, from [Storage::open_readonly].
+#[derive(Clone)]
+pub struct ReadStorage {
+    inner: Storage,
+}
+
+impl ReadStorage {
+    pub(super) fn new(inner: Storage) -> Self {
+        assert!(inner.read_only, "storage was opened for writing");
+        Self { inner }
+    }
+
+    /// Obtain the highest height, as stored by the writer.
+    pub async fn highest_height(&self) -> Result<BlockHeight, StorageError> {
+        self.inner.stored_tip_height().await
+    }
+
+    /// Obtain a historical SealedState.
+    pub async fn get_state(
+        &self,
+        height: BlockHeight,
+    ) -> Result<SealedState<ForestStore>, StorageError> {
+        self.inner.get_state(height).await
+    }
+
+    /// Obtain just one particular Block.
+    pub async fn get_block(&self, height: BlockHeight) -> Result<Block, StorageError> {
+        self.inner.get_block(height).await
+    }
+
+    /// Obtain a historical ConsensusProof.
+    pub async fn get_consensus(&self, height: BlockHeight) -> Result<ConsensusProof, StorageError> {
+        self.inner.get_consensus(height).await
+    }
+
+    /// Checks the whole database. See [Storage::verify].
+    pub async fn verify(&self) -> Result<VerifyReport, StorageError> {
+        self.inner.verify().await
+    }
+
+    /// Backs up the database under another database path, returning the highest height.
+    pub async fn backup(&self, dest: PathBuf) -> Result<BlockHeight, StorageError> {
+        self.inner.backup(dest).await
+    }
+
+    /// Exports a state to a snapshot file. See [Storage::export_snapshot].
+    pub async fn export_snapshot(
+        &self,
+        height: BlockHeight,
+        path: PathBuf,
+    ) -> Result<u64, StorageError> {
+        self.inner.export_snapshot(height, path).await
+    }
+}
@@ -0,0 +1,268 @@
+use std::time::Instant;
+
+use anyhow::Context;
+use melstructs::{Block, Header};
+use rusqlite::{params, OptionalExtension, Transaction};
+
+use super::storage::{
+    compress_block, index_block_hash, index_transactions, BLOCK_FORMAT_LZ4, BLOCK_FORMAT_RAW,
+};
+
+/// One step in the history of the sqlite schema.
+struct Migration {
+    description: &'static str,
+    apply: fn(&Transaction) -> anyhow::Result<()>,
+    /// Rewrites existing rows after `apply`, one committed chunk at a time.
+    backfill: Option<Backfill>,
+}
+
+/// Rewrites one chunk of rows from a cursor, returning where the next one starts.
+type Backfill = fn(&Transaction, u64) -> anyhow::Result<Option<u64>>;
+
+/// Every migration, in order. Only ever append, and tolerate tables that already exist.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "base tables",
+        apply: base_tables,
+        backfill: None,
+    },
+    Migration {
+        description: "transaction index",
+        apply: transaction_index,
+        backfill: Some(index_old_transactions),
+    },
+    Migration {
+        description: "block hash index",
+        apply: block_hash_index,
+        backfill: Some(index_old_block_hashes),
+    },
+    Migration {
+        description: "per-epoch stakeset cache",
+        apply: stakeset_cache,
+        backfill: None,
+    },
+    Migration {
+        description: "compressed blocks",
+        apply: compressed_blocks,
+        backfill: Some(compress_old_blocks),
+    },
+    Migration {
+        description: "mempool journal",
+        apply: mempool_journal,
+        backfill: None,
+    },
+];
+
+/// The schema version that this version of melnode reads and writes.
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// Reads the schema version of a sqlite database, 0 if it predates versioning.
+pub fn schema_version(conn: &rusqlite::Connection) -> anyhow::Result<u32> {
+    let has_misc: bool = conn.query_row(
+        "select count(*) > 0 from sqlite_master where type = 'table' and name = 'misc'",
+        params![],
+        |r| r.get(0),
+    )?;
+    if !has_misc {
+        return Ok(0);
+    }
+    let version: Option<u32> = conn
+        .query_row(
+            "select value from misc where key = 'schema_version'",
+            params![],
+            |r| r.get(0),
+        )
+        .optional()?;
+    Ok(version.unwrap_or_default())
+}
+
+/// Brings a sqlite database up to [SCHEMA_VERSION], returning the version it was at.
+pub fn migrate(conn: &mut rusqlite::Connection) -> anyhow::Result<u32> {
+    let from = schema_version(conn)?;
+    if from > SCHEMA_VERSION {
+        anyhow::bail!(
+            "database is at schema version {}, but this melnode only understands up to {}",
+            from,
+            SCHEMA_VERSION
+        );
+    }
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(from as usize) {
+        let version = version as u32 + 1;
+        log::info!(
+            "migrating sqlite schema to version {}: {}",
+            version,
+            migration.description
+        );
+        let start = Instant::now();
+        let mut txn = conn.transaction()?;
+        (migration.apply)(&txn)
+            .with_context(|| format!("migration to version {} failed", version))?;
+        if let Some(backfill) = migration.backfill {
+            // the version only changes once the backfill is done, so an interrupted one resumes from its cursor
+            let mut cursor: u64 = txn
+                .query_row(
+                    "select value from misc where key = 'migration_cursor'",
+                    params![],
+                    |r| r.get(0),
+                )
+                .optional()?
+                .unwrap_or_default();
+            while let Some(next) = backfill(&txn, cursor)
+                .with_context(|| format!("migration to version {} failed", version))?
+            {
+                cursor = next;
+                txn.execute(
+                    "insert into misc (key, value) values ('migration_cursor', $1) on conflict(key) do update set value = excluded.value",
+                    params![cursor],
+                )?;
+                txn.commit()?;
+                txn = conn.transaction()?;
+            }
+            txn.execute("delete from misc where key = 'migration_cursor'", params![])?;
+        }
+        txn.execute(
+            "insert into misc (key, value) values ('schema_version', $1) on conflict(key) do update set value = excluded.value",
+            params![version],
+        )?;
+        txn.commit()?;
+        log::debug!(
+            "migrated to version {} in {:.2}s",
+            version,
+            start.elapsed().as_secs_f64()
+        );
+    }
+    Ok(from)
+}
+
+fn base_tables(txn: &Transaction) -> anyhow::Result<()> {
+    txn.execute("create table if not exists history (height primary key not null, header not null, block not null)", params![])?;
+    txn.execute(
+        "create table if not exists consensus_proofs (height primary key not null, proof not null)",
+        params![],
+    )?;
+    txn.execute(
+        "create table if not exists stakes (txhash primary key not null, height not null, stake_doc not null)",
+        params![],
+    )?;
+    txn.execute(
+        "create table if not exists misc (key primary key not null, value not null)",
+        params![],
+    )?;
+    Ok(())
+}
+
+fn transaction_index(txn: &Transaction) -> anyhow::Result<()> {
+    txn.execute(
+        "create table if not exists transactions (txhash primary key not null, height not null, idx not null)",
+        params![],
+    )?;
+    Ok(())
+}
+
+/// Indexes the transactions of the blocks at heights from `from` onwards, a thousand at a time.
+fn index_old_transactions(txn: &Transaction, from: u64) -> anyhow::Result<Option<u64>> {
+    let batch = txn
+        .prepare_cached(
+            "select height, block from history where height >= $1 order by height limit 1000",
+        )?
+        .query_map(params![from], |r| {
+            Ok((r.get::<_, u64>(0)?, r.get::<_, Vec<u8>>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    let Some(&(last, _)) = batch.last() else {
+        return Ok(None);
+    };
+    for (_, block) in batch {
+        let block: Block = stdcode::deserialize(&block)?;
+        index_transactions(txn, &block)?;
+    }
+    log::debug!("indexed transactions up to height {}", last);
+    Ok(Some(last + 1))
+}
+
+fn block_hash_index(txn: &Transaction) -> anyhow::Result<()> {
+    txn.execute(
+        "create table if not exists block_hashes (hash primary key not null, height not null)",
+        params![],
+    )?;
+    Ok(())
+}
+
+/// Indexes the hashes of the headers at heights from `from` onwards, a thousand at a time.
+fn index_old_block_hashes(txn: &Transaction, from: u64) -> anyhow::Result<Option<u64>> {
+    let batch = txn
+        .prepare_cached(
+            "select height, header from history where height >= $1 order by height limit 1000",
+        )?
+        .query_map(params![from], |r| {
+            Ok((r.get::<_, u64>(0)?, r.get::<_, Vec<u8>>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    let Some(&(last, _)) = batch.last() else {
+        return Ok(None);
+    };
+    for (_, header) in batch {
+        let header: Header = stdcode::deserialize(&header)?;
+        index_block_hash(txn, &header)?;
+    }
+    log::debug!("indexed block hashes up to height {}", last);
+    Ok(Some(last + 1))
+}
+
+fn stakeset_cache(txn: &Transaction) -> anyhow::Result<()> {
+    txn.execute(
+        "create table if not exists stakeset_epochs (epoch primary key not null, stakes not null)",
+        params![],
+    )?;
+    txn.execute(
+        "create index if not exists stakes_height on stakes (height)",
+        params![],
+    )?;
+    Ok(())
+}
+
+fn compressed_blocks(txn: &Transaction) -> anyhow::Result<()> {
+    // an interrupted backfill reruns this
+    let exists: bool = txn.query_row(
+        "select count(*) > 0 from pragma_table_info('history') where name = 'block_format'",
+        params![],
+        |r| r.get(0),
+    )?;
+    if !exists {
+        txn.execute(
+            "alter table history add column block_format not null default 0",
+            params![],
+        )?;
+    }
+    Ok(())
+}
+
+/// Compresses the raw blocks at heights from `from` onwards, a thousand at a time.
+fn compress_old_blocks(txn: &Transaction, from: u64) -> anyhow::Result<Option<u64>> {
+    let batch = txn
+        .prepare_cached(
+            "select height, block from history where height >= $1 and block_format = $2 order by height limit 1000",
+        )?
+        .query_map(params![from, BLOCK_FORMAT_RAW], |r| {
+            Ok((r.get::<_, u64>(0)?, r.get::<_, Vec<u8>>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    let Some(&(last, _)) = batch.last() else {
+        return Ok(None);
+    };
+    let mut update =
+        txn.prepare_cached("update history set block = $1, block_format = $2 where height = $3")?;
+    for (height, block) in batch {
+        update.execute(params![compress_block(&block), BLOCK_FORMAT_LZ4, height])?;
+    }
+    log::debug!("compressed blocks up to height {}", last);
+    Ok(Some(last + 1))
+}
+
+fn mempool_journal(txn: &Transaction) -> anyhow::Result<()> {
+    txn.execute(
+        "create table if not exists mempool_journal (txhash primary key not null, tx not null, received not null)",
+        params![],
+    )?;
+    Ok(())
+}
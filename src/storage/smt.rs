@@ -1,30 +1,697 @@
-use novasmt::ContentAddrStore;
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-/// A meshanina-backed autosmt backend
-pub struct MeshaCas {
-    inner: meshanina::Mapping,
+use anyhow::Context;
+use arc_swap::ArcSwap;
+use novasmt::{hash_data, hash_node, ContentAddrStore, Hashed};
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+
+use super::{mesha_reader::MeshaReader, sqlite_cas::SqliteCas, StorageError};
+
+/// How long the nodes dropped by [ForestStore::compact] stay readable.
+const RETIRE_GRACE: Duration = Duration::from_secs(60);
+
+/// Rough per-node overhead of the node cache.
+const CACHE_ENTRY_OVERHEAD: usize = 64;
+
+/// Which kind of database the SMT forest is kept in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ForestBackend {
+    /// An append-only meshanina file, `merkle.db`.
+    #[default]
+    Meshanina,
+    /// A table in a separate sqlite file, `merkle.sqlite`.
+    Sqlite,
+    /// Memory only, for tests and simnets.
+    Memory,
+}
+
+impl ForestBackend {
+    /// Opens the forest kept in a database folder, creating it if needed.
+    pub fn open(self, folder: &Path) -> anyhow::Result<ForestStore> {
+        let backend = match self {
+            ForestBackend::Meshanina => {
+                let path = folder.join("merkle.db");
+                Backend::Mapping(
+                    meshanina::Mapping::open(&path).context("cannot open mesha")?,
+                    path,
+                )
+            }
+            ForestBackend::Sqlite => Backend::Sqlite(
+                SqliteCas::open(&folder.join("merkle.sqlite"), false)
+                    .context("cannot open sqlite forest")?,
+            ),
+            ForestBackend::Memory => Backend::Memory(Default::default()),
+        };
+        Ok(ForestStore::new(backend))
+    }
+
+    /// Opens the forest in a database folder read-only. Inserts are kept in memory.
+    pub fn open_readonly(self, folder: &Path) -> anyhow::Result<ForestStore> {
+        let backend = match self {
+            ForestBackend::Meshanina => Backend::Reader(
+                MeshaReader::open(&folder.join("merkle.db")).context("cannot open mesha")?,
+            ),
+            ForestBackend::Sqlite => Backend::Sqlite(
+                SqliteCas::open(&folder.join("merkle.sqlite"), true)
+                    .context("cannot open sqlite forest")?,
+            ),
+            ForestBackend::Memory => {
+                anyhow::bail!("an in-memory forest cannot be opened by another process")
+            }
+        };
+        Ok(ForestStore::new(backend))
+    }
+
+    /// Deletes the forest kept in a database folder, if there is one.
+    pub(super) fn remove(self, folder: &Path) -> anyhow::Result<()> {
+        let files: &[&str] = match self {
+            ForestBackend::Meshanina => &["merkle.db", "merkle.db.compact"],
+            ForestBackend::Sqlite => &["merkle.sqlite", "merkle.sqlite-wal", "merkle.sqlite-shm"],
+            ForestBackend::Memory => &[],
+        };
+        for file in files {
+            match std::fs::remove_file(folder.join(file)) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Display for ForestBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ForestBackend::Meshanina => "meshanina",
+            ForestBackend::Sqlite => "sqlite",
+            ForestBackend::Memory => "memory",
+        }
+        .fmt(f)
+    }
+}
+
+impl FromStr for ForestBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "meshanina" => Ok(ForestBackend::Meshanina),
+            "sqlite" => Ok(ForestBackend::Sqlite),
+            "memory" => Ok(ForestBackend::Memory),
+            other => anyhow::bail!("unknown forest backend {:?}", other),
+        }
+    }
+}
+
+/// The backend holding the SMT forest.
+pub struct ForestStore {
+    inner: ArcSwap<Backend>,
+    retired: Arc<Mutex<Option<Arc<Backend>>>>,
+    /// The backend a running [ForestStore::compact] fills, which also gets every insert.
+    compacting: RwLock<Option<Arc<Backend>>>,
+    cache: Option<NodeCache>,
+}
+
+/// Recently read or written nodes.
+struct NodeCache {
+    nodes: moka::sync::Cache<Hashed, Arc<[u8]>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// How well a [ForestStore]'s node cache is doing.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct ForestCacheStats {
+    /// Lookups answered from the cache.
+    pub hits: u64,
+    /// Lookups that had to go to the backend.
+    pub misses: u64,
+    /// Number of cached nodes.
+    pub entries: u64,
+    /// Approximate memory used by the cached nodes, in bytes.
+    pub bytes: u64,
+}
+
+enum Backend {
+    /// A meshanina database this process owns, and where it lives.
+    Mapping(meshanina::Mapping, PathBuf),
+    /// A read-only view of a meshanina database another process may write to.
+    Reader(MeshaReader),
+    Sqlite(SqliteCas),
+    Memory(RwLock<HashMap<Vec<u8>, Vec<u8>>>),
 }
 
-impl MeshaCas {
-    /// Takes exclusively ownership of a Meshanina database and creates an autosmt backend.
-    pub fn new(db: meshanina::Mapping) -> Self {
-        Self { inner: db }
+impl Backend {
+    fn flush(&self) -> Result<(), StorageError> {
+        match self {
+            Backend::Mapping(mapping, _) => mapping.flush(),
+            Backend::Sqlite(cas) => cas.flush()?,
+            Backend::Reader(_) | Backend::Memory(_) => {}
+        }
+        Ok(())
     }
+}
+
+impl ContentAddrStore for Backend {
+    fn get<'a>(&'a self, key: &[u8]) -> Option<Cow<'a, [u8]>> {
+        match self {
+            // meshanina keys have always been hashed once more
+            Backend::Mapping(mapping, _) => mapping
+                .get(tmelcrypt::hash_single(key).0)
+                .map(|val| Cow::Owned(val.to_vec())),
+            Backend::Reader(reader) => reader.get(tmelcrypt::hash_single(key).0).map(Cow::Owned),
+            Backend::Sqlite(cas) => cas.get(key),
+            Backend::Memory(map) => map.read().get(key).cloned().map(Cow::Owned),
+        }
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) {
+        match self {
+            Backend::Mapping(mapping, _) => mapping.insert(tmelcrypt::hash_single(key).0, value),
+            Backend::Reader(reader) => reader.insert(tmelcrypt::hash_single(key).0, value),
+            Backend::Sqlite(cas) => cas.insert(key, value),
+            Backend::Memory(map) => {
+                map.write().insert(key.to_vec(), value.to_vec());
+            }
+        }
+    }
+}
+
+impl ForestStore {
+    fn new(backend: Backend) -> Self {
+        Self {
+            inner: ArcSwap::from_pointee(backend),
+            retired: Default::default(),
+            compacting: Default::default(),
+            cache: None,
+        }
+    }
+
+    /// Puts a node cache of roughly `max_bytes` in front of the backend. Zero disables it.
+    pub fn with_cache(mut self, max_bytes: u64) -> Self {
+        self.cache = (max_bytes > 0).then(|| NodeCache {
+            nodes: moka::sync::Cache::builder()
+                .weigher(|_, node: &Arc<[u8]>| {
+                    (node.len() + 32 + CACHE_ENTRY_OVERHEAD)
+                        .try_into()
+                        .unwrap_or(u32::MAX)
+                })
+                .max_capacity(max_bytes)
+                .build(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        });
+        self
+    }
+
+    /// Hit and miss counts of the node cache, if there is one.
+    pub fn cache_stats(&self) -> Option<ForestCacheStats> {
+        self.cache.as_ref().map(|cache| ForestCacheStats {
+            hits: cache.hits.load(Ordering::Relaxed),
+            misses: cache.misses.load(Ordering::Relaxed),
+            entries: cache.nodes.entry_count(),
+            bytes: cache.nodes.weighted_size(),
+        })
+    }
+
+    /// Which kind of database the forest is currently kept in.
+    pub fn backend(&self) -> ForestBackend {
+        match self.inner.load().as_ref() {
+            Backend::Mapping(..) | Backend::Reader(_) => ForestBackend::Meshanina,
+            Backend::Sqlite(_) => ForestBackend::Sqlite,
+            Backend::Memory(_) => ForestBackend::Memory,
+        }
+    }
+
+    /// Syncs to disk, failing if some node since the last flush couldn't be written.
+    pub fn flush(&self) -> Result<(), StorageError> {
+        let compacting = self.compacting.read();
+        self.inner.load().flush()?;
+        if let Some(compacted) = compacting.as_ref() {
+            compacted.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Copies everything flushed so far into another database folder. Read-only forests only.
+    pub fn copy_flushed(&self, folder: &Path) -> anyhow::Result<()> {
+        match self.inner.load().as_ref() {
+            Backend::Reader(reader) => reader.copy_to(&folder.join("merkle.db")),
+            Backend::Sqlite(cas) => cas.copy_to(&folder.join("merkle.sqlite")),
+            _ => anyhow::bail!("only read-only forests can be copied"),
+        }
+    }
+
+    /// Drops the nodes unreachable from `roots`, which is called once inserts reach both forests.
+    pub fn compact(
+        &self,
+        roots: impl FnOnce() -> anyhow::Result<Vec<Hashed>>,
+    ) -> anyhow::Result<u64> {
+        let current = self.inner.load_full();
+        let compacted = Arc::new(match current.as_ref() {
+            Backend::Mapping(_, path) => {
+                let compact_path = path.with_extension("db.compact");
+                let _ = std::fs::remove_file(&compact_path);
+                Backend::Mapping(
+                    meshanina::Mapping::open(&compact_path)
+                        .context("cannot open compacted mesha")?,
+                    path.clone(),
+                )
+            }
+            Backend::Memory(_) => Backend::Memory(Default::default()),
+            Backend::Sqlite(cas) => return cas.compact(roots, RETIRE_GRACE),
+            Backend::Reader(_) => anyhow::bail!("cannot compact a read-only forest"),
+        });
+        *self.compacting.write() = Some(compacted.clone());
+        let copied = roots()
+            .and_then(|roots| copy_reachable(self, compacted.as_ref(), roots))
+            .and_then(|copied| {
+                if let Backend::Mapping(mapping, path) = compacted.as_ref() {
+                    mapping.flush();
+                    std::fs::rename(path.with_extension("db.compact"), path)?;
+                }
+                Ok(copied)
+            });
+        // swapped under the lock, so that no insert lands in the old backend only
+        let mut compacting = self.compacting.write();
+        if copied.is_ok() {
+            self.retire(compacted);
+        }
+        *compacting = None;
+        copied
+    }
+
+    /// Moves to another forest. The old one stays readable for a grace period.
+    pub fn replace(&self, other: ForestStore) {
+        self.retire(other.inner.into_inner())
+    }
+
+    fn retire(&self, backend: impl Into<Arc<Backend>>) {
+        let old = self.inner.swap(backend.into());
+        *self.retired.lock() = Some(old.clone());
+        let retired = self.retired.clone();
+        smolscale::spawn(async move {
+            smol::Timer::after(RETIRE_GRACE).await;
+            let mut retired = retired.lock();
+            if retired
+                .as_ref()
+                .map(|r| Arc::ptr_eq(r, &old))
+                .unwrap_or_default()
+            {
+                *retired = None;
+            }
+        })
+        .detach();
+    }
+}
 
-    /// Syncs to disk.
-    pub fn flush(&self) {
-        self.inner.flush()
+impl ForestStore {
+    fn get_uncached(&self, key: &[u8]) -> Option<Vec<u8>> {
+        match self.inner.load().get(key) {
+            Some(val) => Some(val.into_owned()),
+            None => Some(self.retired.lock().as_ref()?.get(key)?.into_owned()),
+        }
     }
 }
 
-impl ContentAddrStore for MeshaCas {
-    fn get<'a>(&'a self, key: &[u8]) -> Option<std::borrow::Cow<'a, [u8]>> {
-        Some(std::borrow::Cow::Owned(
-            self.inner.get(tmelcrypt::hash_single(key).0)?.to_vec(),
-        ))
+impl ContentAddrStore for ForestStore {
+    fn get<'a>(&'a self, key: &[u8]) -> Option<Cow<'a, [u8]>> {
+        let (Some(cache), Ok(hash)) = (&self.cache, Hashed::try_from(key)) else {
+            return self.get_uncached(key).map(Cow::Owned);
+        };
+        // nodes are content-addressed, so a cached node never goes stale, even across compactions
+        if let Some(val) = cache.nodes.get(&hash) {
+            cache.hits.fetch_add(1, Ordering::Relaxed);
+            // this copy can't go: a borrowed `Cow` must outlive `&self`, but moka may evict and drop its `Arc` at any moment, and `get` can't hand out the `Arc` itself
+            return Some(Cow::Owned(val.to_vec()));
+        }
+        cache.misses.fetch_add(1, Ordering::Relaxed);
+        let val = self.get_uncached(key)?;
+        cache.nodes.insert(hash, val.as_slice().into());
+        Some(Cow::Owned(val))
     }
 
     fn insert(&self, key: &[u8], value: &[u8]) {
-        self.inner.insert(tmelcrypt::hash_single(key).0, value);
+        let compacting = self.compacting.read();
+        self.inner.load().insert(key, value);
+        if let Some(compacted) = compacting.as_ref() {
+            compacted.insert(key, value);
+        }
+        drop(compacting);
+        if let (Some(cache), Ok(hash)) = (&self.cache, Hashed::try_from(key)) {
+            cache.nodes.insert(hash, value.into());
+        }
     }
 }
+
+/// Returns the hashes of the children of a raw novasmt node.
+pub fn node_children(node: &[u8]) -> impl Iterator<Item = Hashed> + '_ {
+    let hexary = node.first().copied().unwrap_or_default() != 0 && node.len() == 1 + 8 + 32 * 16;
+    let children: &[u8] = if hexary { &node[9..] } else { &[] };
+    children
+        .chunks_exact(32)
+        .map(|c| Hashed::try_from(c).unwrap())
+        .filter(|c| c != &Hashed::default())
+}
+
+/// Computes the hash of a raw novasmt node, or `None` if it's malformed.
+pub fn node_hash(node: &[u8]) -> Option<Hashed> {
+    match *node.first()? {
+        0 => {
+            // a "single" node commits to the last 4*height bits of its key
+            let height = *node.get(1)? as usize * 4;
+            let key: Hashed = node.get(2..34)?.try_into().ok()?;
+            if height > 256 {
+                return None;
+            }
+            let mut acc = hash_data(&node[34..]);
+            for bit in (256 - height..256).rev() {
+                acc = if (key[bit / 8] >> (7 - bit % 8)) & 1 == 1 {
+                    hash_node(Hashed::default(), acc)
+                } else {
+                    hash_node(acc, Hashed::default())
+                };
+            }
+            Some(acc)
+        }
+        _ => {
+            if node.len() != 1 + 8 + 32 * 16 {
+                return None;
+            }
+            let mut level: Vec<Hashed> = node[9..]
+                .chunks_exact(32)
+                .map(|c| Hashed::try_from(c).unwrap())
+                .collect();
+            while level.len() > 1 {
+                level = level
+                    .chunks_exact(2)
+                    .map(|pair| hash_node(pair[0], pair[1]))
+                    .collect();
+            }
+            Some(level[0])
+        }
+    }
+}
+
+/// Where a node sits in a tree: its height, and the key nibbles leading down to it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NodePos {
+    height: u8,
+    path: Hashed,
+}
+
+impl NodePos {
+    /// The position of a tree's root.
+    pub const ROOT: Self = Self {
+        height: 64,
+        path: [0; 32],
+    };
+
+    /// The position of the `index`th child of a hexary node here.
+    fn child(self, index: usize) -> Self {
+        let depth = (64 - self.height) as usize;
+        let mut path = self.path;
+        path[depth / 2] |= (index as u8) << if depth.is_multiple_of(2) { 4 } else { 0 };
+        Self {
+            height: self.height - 1,
+            path,
+        }
+    }
+
+    /// Whether a key starts with the nibbles leading down to here.
+    fn leads_to(self, key: &Hashed) -> bool {
+        let depth = (64 - self.height) as usize;
+        key[..depth / 2] == self.path[..depth / 2]
+            && (depth.is_multiple_of(2) || key[depth / 2] >> 4 == self.path[depth / 2] >> 4)
+    }
+}
+
+/// Checks that a raw node has the given hash and fits the given position, returning its children's positions.
+///
+/// The hash doesn't cover a hexary node's height and count, or the top of a single node's key, so those are checked against the position instead; counts need the children, see [check_count].
+pub fn check_node(
+    node: &[u8],
+    hash: Hashed,
+    pos: NodePos,
+) -> anyhow::Result<Vec<(Hashed, NodePos)>> {
+    if node_hash(node) != Some(hash) {
+        anyhow::bail!("SMT node {} does not match its hash", hex::encode(hash));
+    }
+    let height = if node[0] == 0 { node[1] } else { node[0] };
+    if height != pos.height {
+        anyhow::bail!(
+            "SMT node {} has height {} but sits at height {}",
+            hex::encode(hash),
+            height,
+            pos.height
+        );
+    }
+    if node[0] == 0 {
+        if !pos.leads_to(node[2..34].try_into().unwrap()) {
+            anyhow::bail!("SMT node {} has a key off its path", hex::encode(hash));
+        }
+        return Ok(vec![]);
+    }
+    Ok(node[9..]
+        .chunks_exact(32)
+        .enumerate()
+        .map(|(index, c)| (Hashed::try_from(c).unwrap(), pos.child(index)))
+        .filter(|(c, _)| c != &Hashed::default())
+        .collect())
+}
+
+/// The number of elements a well-formed raw node claims to have under it.
+fn node_count(node: &[u8]) -> u64 {
+    match node.first() {
+        Some(0) => 1,
+        _ => node
+            .get(1..9)
+            .map(|c| u64::from_le_bytes(c.try_into().unwrap()))
+            .unwrap_or_default(),
+    }
+}
+
+/// Checks that a hexary node's count is the sum of its children's, unless some child isn't in `store` yet.
+pub fn check_count(store: &impl ContentAddrStore, node: &[u8]) -> anyhow::Result<()> {
+    if node.first() == Some(&0) {
+        return Ok(());
+    }
+    let mut sum: u64 = 0;
+    for child in node_children(node) {
+        let Some(child) = store.get(&child) else {
+            return Ok(());
+        };
+        sum = sum
+            .checked_add(node_count(&child))
+            .context("SMT node count overflows")?;
+    }
+    if sum != node_count(node) {
+        anyhow::bail!(
+            "SMT node {} counts {} elements but has {}",
+            hex::encode(node_hash(node).unwrap_or_default()),
+            node_count(node),
+            sum
+        );
+    }
+    Ok(())
+}
+
+/// Takes untrusted SMT nodes for some trusted roots, parents before children, and puts each into a store once its whole subtree is there and checked.
+///
+/// A node in the store therefore always has its whole subtree there, so nothing bad ever sticks around to be trusted by a later import.
+pub struct SmtImport<'a, C: ContentAddrStore> {
+    store: &'a C,
+    /// Nodes still to come, most recently found last.
+    frontier: Vec<Hashed>,
+    expected: HashMap<Hashed, NodePos>,
+    /// Checked nodes still waiting on some children, with how many.
+    pending: HashMap<Hashed, (Vec<u8>, usize)>,
+    /// The pending parents of each node still to come.
+    parents: HashMap<Hashed, Vec<Hashed>>,
+}
+
+impl<'a, C: ContentAddrStore> SmtImport<'a, C> {
+    /// Starts importing the trees under some roots, skipping whatever is already in `store`.
+    pub fn new(store: &'a C, roots: impl IntoIterator<Item = Hashed>) -> Self {
+        let mut this = Self {
+            store,
+            frontier: vec![],
+            expected: HashMap::new(),
+            pending: HashMap::new(),
+            parents: HashMap::new(),
+        };
+        for root in roots {
+            this.expect(root, NodePos::ROOT);
+        }
+        this
+    }
+
+    /// Waits for a node unless it's already in the store, returning whether it's still to come.
+    fn expect(&mut self, hash: Hashed, pos: NodePos) -> bool {
+        if hash == Hashed::default() || self.store.get(&hash).is_some() {
+            return false;
+        }
+        if !self.expected.contains_key(&hash) && !self.pending.contains_key(&hash) {
+            self.expected.insert(hash, pos);
+            self.frontier.push(hash);
+        }
+        true
+    }
+
+    /// Takes up to `max` of the nodes still to come, depth-first.
+    pub fn next_wanted(&mut self, max: usize) -> Vec<Hashed> {
+        let mut wanted = vec![];
+        while wanted.len() < max {
+            let Some(hash) = self.frontier.pop() else {
+                break;
+            };
+            if self.expected.contains_key(&hash) {
+                wanted.push(hash);
+            }
+        }
+        wanted
+    }
+
+    /// Whether a node is still to come.
+    pub fn expects(&self, hash: &Hashed) -> bool {
+        self.expected.contains_key(hash)
+    }
+
+    /// Checks and takes a node, which must be one still to come or one already in the store.
+    pub fn insert(&mut self, node: Vec<u8>) -> anyhow::Result<()> {
+        let hash = node_hash(&node).context("malformed SMT node")?;
+        let Some(pos) = self.expected.remove(&hash) else {
+            if self.store.get(&hash).is_some() {
+                return Ok(());
+            }
+            anyhow::bail!("unexpected SMT node {}", hex::encode(hash));
+        };
+        let mut missing = 0;
+        for (child, pos) in check_node(&node, hash, pos)? {
+            if self.expect(child, pos) {
+                let parents = self.parents.entry(child).or_default();
+                if !parents.contains(&hash) {
+                    parents.push(hash);
+                    missing += 1;
+                }
+            }
+        }
+        if missing > 0 {
+            self.pending.insert(hash, (node, missing));
+            return Ok(());
+        }
+        let mut ready = vec![(hash, node)];
+        while let Some((hash, node)) = ready.pop() {
+            check_count(self.store, &node)?;
+            self.store.insert(&hash, &node);
+            for parent in self.parents.remove(&hash).unwrap_or_default() {
+                let (_, missing) = self.pending.get_mut(&parent).unwrap();
+                *missing -= 1;
+                if *missing == 0 {
+                    let (node, _) = self.pending.remove(&parent).unwrap();
+                    ready.push((parent, node));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails unless every node has come.
+    pub fn finish(self) -> anyhow::Result<()> {
+        if let Some(hash) = self.expected.keys().next() {
+            anyhow::bail!("missing SMT node {}", hex::encode(hash));
+        }
+        Ok(())
+    }
+}
+
+/// Calls `f` on every node reachable from some SMT roots, returning how many there were.
+pub fn for_each_reachable(
+    store: &impl ContentAddrStore,
+    roots: impl IntoIterator<Item = Hashed>,
+    f: impl FnMut(Hashed, &[u8]) -> anyhow::Result<()>,
+) -> anyhow::Result<u64> {
+    for_each_reachable_unseen(store, roots, &mut HashSet::new(), f)
+}
+
+/// Like [for_each_reachable], but skips nodes in `seen`, and adds visited ones to it.
+pub fn for_each_reachable_unseen(
+    store: &impl ContentAddrStore,
+    roots: impl IntoIterator<Item = Hashed>,
+    seen: &mut HashSet<Hashed>,
+    mut f: impl FnMut(Hashed, &[u8]) -> anyhow::Result<()>,
+) -> anyhow::Result<u64> {
+    let mut stack: Vec<Hashed> = roots
+        .into_iter()
+        .filter(|r| r != &Hashed::default())
+        .collect();
+    let mut count = 0;
+    while let Some(hash) = stack.pop() {
+        if !seen.insert(hash) {
+            continue;
+        }
+        let node = store
+            .get(&hash)
+            .ok_or_else(|| anyhow::anyhow!("dangling SMT node {}", hex::encode(hash)))?;
+        stack.extend(node_children(&node).filter(|c| !seen.contains(c)));
+        f(hash, &node)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Like [for_each_reachable_unseen], but checks each node's structure at its place in its tree, calling `bad` on each that fails.
+pub fn check_reachable_unseen(
+    store: &impl ContentAddrStore,
+    roots: impl IntoIterator<Item = Hashed>,
+    seen: &mut HashSet<Hashed>,
+    mut bad: impl FnMut(Hashed, anyhow::Error),
+) -> anyhow::Result<u64> {
+    let mut stack: Vec<(Hashed, NodePos)> = roots
+        .into_iter()
+        .filter(|r| r != &Hashed::default())
+        .map(|r| (r, NodePos::ROOT))
+        .collect();
+    let mut count = 0;
+    while let Some((hash, pos)) = stack.pop() {
+        if !seen.insert(hash) {
+            continue;
+        }
+        let node = store
+            .get(&hash)
+            .ok_or_else(|| anyhow::anyhow!("dangling SMT node {}", hex::encode(hash)))?;
+        match check_node(&node, hash, pos).and_then(|children| {
+            check_count(store, &node)?;
+            Ok(children)
+        }) {
+            Ok(children) => stack.extend(children.into_iter().filter(|(c, _)| !seen.contains(c))),
+            Err(err) => bad(hash, err),
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Copies every node reachable from some SMT roots into another store.
+pub fn copy_reachable(
+    from: &impl ContentAddrStore,
+    to: &impl ContentAddrStore,
+    roots: impl IntoIterator<Item = Hashed>,
+) -> anyhow::Result<u64> {
+    for_each_reachable(from, roots, |hash, node| {
+        to.insert(&hash, node);
+        Ok(())
+    })
+}
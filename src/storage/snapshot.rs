@@ -0,0 +1,93 @@
+use std::io::{Read, Write};
+
+use melstructs::{Block, ConsensusProof, StakeDoc, TxHash};
+use novasmt::Hashed;
+use serde::{Deserialize, Serialize};
+use stdcode::StdcodeSerializeExt;
+
+const SNAPSHOT_MAGIC: &[u8; 8] = b"melsnap1";
+
+/// Everything besides SMT nodes needed to restore a state from a snapshot.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SnapshotMeta {
+    /// The block that produced the state.
+    pub block: Block,
+    /// The consensus proof for the block.
+    pub cproof: ConsensusProof,
+    /// Every stake that is still live at the block's height.
+    pub stakes: Vec<(TxHash, StakeDoc)>,
+}
+
+/// Writes a state snapshot: a magic, the length-prefixed [SnapshotMeta], then raw nodes.
+pub struct SnapshotWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> SnapshotWriter<W> {
+    /// Starts a snapshot with the given metadata.
+    pub fn new(mut inner: W, meta: &SnapshotMeta) -> std::io::Result<Self> {
+        let meta = meta.stdcode();
+        inner.write_all(SNAPSHOT_MAGIC)?;
+        inner.write_all(&(meta.len() as u64).to_le_bytes())?;
+        inner.write_all(&meta)?;
+        Ok(Self { inner })
+    }
+
+    /// Appends a raw SMT node.
+    pub fn write_node(&mut self, hash: Hashed, node: &[u8]) -> std::io::Result<()> {
+        self.inner.write_all(&hash)?;
+        self.inner.write_all(&(node.len() as u32).to_le_bytes())?;
+        self.inner.write_all(node)
+    }
+
+    /// Finishes the snapshot, returning the inner writer.
+    pub fn finish(mut self) -> std::io::Result<W> {
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+/// Reads a snapshot written by [SnapshotWriter], verifying nothing.
+pub struct SnapshotReader<R: Read> {
+    inner: R,
+}
+
+impl<R: Read> SnapshotReader<R> {
+    /// Starts reading a snapshot, returning the reader and the snapshot's metadata.
+    pub fn new(mut inner: R) -> anyhow::Result<(Self, SnapshotMeta)> {
+        let mut magic = [0u8; 8];
+        inner.read_exact(&mut magic)?;
+        if &magic != SNAPSHOT_MAGIC {
+            anyhow::bail!("not a melnode state snapshot");
+        }
+        let mut len = [0u8; 8];
+        inner.read_exact(&mut len)?;
+        let meta = read_prefixed(&mut inner, u64::from_le_bytes(len))?;
+        let meta: SnapshotMeta = stdcode::deserialize(&meta)?;
+        Ok((Self { inner }, meta))
+    }
+
+    /// Reads the next raw SMT node, returning `None` at the end of the snapshot.
+    pub fn next_node(&mut self) -> anyhow::Result<Option<(Hashed, Vec<u8>)>> {
+        let mut hash = Hashed::default();
+        match self.inner.read_exact(&mut hash) {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err.into()),
+        }
+        let mut len = [0u8; 4];
+        self.inner.read_exact(&mut len)?;
+        let node = read_prefixed(&mut self.inner, u32::from_le_bytes(len).into())?;
+        Ok(Some((hash, node)))
+    }
+}
+
+/// Reads `len` untrusted bytes, allocating only as they arrive.
+fn read_prefixed(inner: &mut impl Read, len: u64) -> std::io::Result<Vec<u8>> {
+    let mut buf = vec![];
+    inner.take(len).read_to_end(&mut buf)?;
+    if (buf.len() as u64) < len {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(buf)
+}
@@ -0,0 +1,245 @@
+use std::{borrow::Cow, collections::HashMap, path::Path, sync::Arc, time::Duration};
+
+use novasmt::{ContentAddrStore, Hashed};
+use parking_lot::{Mutex, RwLock};
+use rusqlite::{
+    backup::{Backup, StepResult},
+    params, OpenFlags, OptionalExtension,
+};
+
+use crate::autoretry::autoretry_blocking;
+
+use super::{for_each_reachable, StorageError};
+
+/// Nodes copied by a compaction between commits.
+const COMPACT_CHUNK: u64 = 10_000;
+
+/// SMT nodes stored in a sqlite table, keyed by their hash.
+pub(super) struct SqliteCas {
+    inner: Arc<Mutex<Inner>>,
+    /// Nodes inserted into a read-only store, which only live in memory.
+    overlay: Option<RwLock<HashMap<Vec<u8>, Vec<u8>>>>,
+}
+
+struct Inner {
+    conn: rusqlite::Connection,
+    in_transaction: bool,
+    /// Bumped by every compaction, so stale grace timers do nothing.
+    generation: u64,
+    /// Whether the table from before the last compaction is still around.
+    retired: bool,
+    /// Whether a compaction is filling `nodes_compact`.
+    compacting: bool,
+    /// Why an insert since the last flush failed, if one did.
+    failed: Option<StorageError>,
+}
+
+impl Inner {
+    fn begin(&mut self) -> rusqlite::Result<()> {
+        if !self.in_transaction {
+            self.conn.execute_batch("begin")?;
+            self.in_transaction = true;
+        }
+        Ok(())
+    }
+
+    fn commit(&mut self) -> rusqlite::Result<()> {
+        if self.in_transaction {
+            self.conn.execute_batch("commit")?;
+            self.in_transaction = false;
+        }
+        Ok(())
+    }
+
+    fn insert(&self, table: &str, key: &[u8], value: &[u8]) -> rusqlite::Result<()> {
+        self.conn
+            .prepare_cached(&format!(
+                "insert into {} (hash, node) values ($1, $2) on conflict do nothing",
+                table
+            ))?
+            .execute(params![key, value])?;
+        Ok(())
+    }
+}
+
+impl SqliteCas {
+    /// Opens a sqlite forest, creating it if needed. Read-only stores lock nothing.
+    pub fn open(path: &Path, read_only: bool) -> anyhow::Result<Self> {
+        let conn = if read_only {
+            rusqlite::Connection::open_with_flags(
+                path,
+                OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            )?
+        } else {
+            let conn = rusqlite::Connection::open(path)?;
+            conn.query_row("pragma journal_mode=WAL", params![], |_| Ok(()))?;
+            conn.execute("pragma synchronous=normal", params![])?;
+            conn.execute_batch(
+                "create table if not exists nodes (hash blob primary key not null, node blob not null) without rowid;
+                drop table if exists nodes_retired;
+                drop table if exists nodes_compact;",
+            )?;
+            conn
+        };
+        Ok(Self {
+            inner: Arc::new(Mutex::new(Inner {
+                conn,
+                in_transaction: false,
+                generation: 0,
+                retired: false,
+                compacting: false,
+                failed: None,
+            })),
+            overlay: read_only.then(Default::default),
+        })
+    }
+
+    /// Commits everything inserted since the last flush, failing if any of it couldn't be written.
+    pub fn flush(&self) -> Result<(), StorageError> {
+        let mut inner = self.inner.lock();
+        retry(|| inner.commit())?;
+        match inner.failed.take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes a consistent copy of everything committed so far to a new file.
+    pub fn copy_to(&self, path: &Path) -> anyhow::Result<()> {
+        let inner = self.inner.lock();
+        let mut dst = rusqlite::Connection::open(path)?;
+        let backup = Backup::new(&inner.conn, &mut dst)?;
+        while backup.step(-1)? != StepResult::Done {
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        Ok(())
+    }
+
+    /// Drops the nodes unreachable from `roots`. See [super::ForestStore::compact].
+    pub fn compact(
+        &self,
+        roots: impl FnOnce() -> anyhow::Result<Vec<Hashed>>,
+        grace: Duration,
+    ) -> anyhow::Result<u64> {
+        if self.overlay.is_some() {
+            anyhow::bail!("cannot compact a read-only forest");
+        }
+        {
+            let mut inner = self.inner.lock();
+            inner.commit()?;
+            inner.conn.execute_batch(
+                "drop table if exists nodes_retired;
+                drop table if exists nodes_compact;
+                create table nodes_compact (hash blob primary key not null, node blob not null) without rowid;",
+            )?;
+            inner.retired = false;
+            inner.compacting = true;
+        }
+        let mut copied = 0u64;
+        let count = roots().and_then(|roots| {
+            for_each_reachable(self, roots, |hash, node| {
+                let mut inner = self.inner.lock();
+                inner.begin()?;
+                inner.insert("nodes_compact", &hash, node)?;
+                copied += 1;
+                if copied.is_multiple_of(COMPACT_CHUNK) {
+                    inner.commit()?;
+                }
+                Ok(())
+            })
+        });
+        let mut inner = self.inner.lock();
+        inner.compacting = false;
+        let count = match count {
+            Ok(count) => count,
+            Err(err) => {
+                inner.commit()?;
+                inner
+                    .conn
+                    .execute_batch("drop table if exists nodes_compact")?;
+                return Err(err);
+            }
+        };
+        inner.commit()?;
+        inner.conn.execute_batch(
+            "begin;
+            alter table nodes rename to nodes_retired;
+            alter table nodes_compact rename to nodes;
+            commit;",
+        )?;
+        inner.retired = true;
+        inner.generation += 1;
+        let generation = inner.generation;
+        drop(inner);
+        let inner = self.inner.clone();
+        smolscale::spawn(async move {
+            smol::Timer::after(grace).await;
+            let mut inner = inner.lock();
+            if inner.retired && inner.generation == generation {
+                // inside whatever transaction is open, so it lands with the next flush
+                if let Err(err) = inner.conn.execute("drop table nodes_retired", params![]) {
+                    log::warn!("cannot drop retired SMT nodes: {:?}", err);
+                }
+                inner.retired = false;
+            }
+        })
+        .detach();
+        Ok(count)
+    }
+}
+
+impl ContentAddrStore for SqliteCas {
+    fn get<'a>(&'a self, key: &[u8]) -> Option<Cow<'a, [u8]>> {
+        if let Some(val) = self
+            .overlay
+            .as_ref()
+            .and_then(|o| o.read().get(key).cloned())
+        {
+            return Some(Cow::Owned(val));
+        }
+        let inner = self.inner.lock();
+        // there's no way to return the error, and a node that reads as absent would silently read as an empty subtree instead
+        let val = retry(|| match lookup(&inner.conn, "nodes", key)? {
+            None if inner.retired => lookup(&inner.conn, "nodes_retired", key),
+            val => Ok(val),
+        })
+        .expect("cannot read SMT node from sqlite");
+        val.map(Cow::Owned)
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) {
+        if let Some(overlay) = &self.overlay {
+            overlay.write().insert(key.to_vec(), value.to_vec());
+            return;
+        }
+        let mut inner = self.inner.lock();
+        let res = retry(|| {
+            inner.begin()?;
+            inner.insert("nodes", key, value)?;
+            if inner.compacting {
+                inner.insert("nodes_compact", key, value)?;
+            }
+            Ok(())
+        });
+        // the next flush reports it, before anything can point to the missing node
+        if let Err(err) = res {
+            log::error!("cannot write SMT node to sqlite: {}", err);
+            inner.failed.get_or_insert(err);
+        }
+    }
+}
+
+/// Runs a sqlite operation, retrying while the database is busy.
+fn retry<T>(mut op: impl FnMut() -> rusqlite::Result<T>) -> Result<T, StorageError> {
+    autoretry_blocking(|| op().map_err(StorageError::from))
+}
+
+fn lookup(
+    conn: &rusqlite::Connection,
+    table: &str,
+    key: &[u8],
+) -> rusqlite::Result<Option<Vec<u8>>> {
+    conn.prepare_cached(&format!("select node from {} where hash = $1", table))?
+        .query_row(params![key], |r| r.get(0))
+        .optional()
+}
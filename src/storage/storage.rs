@@ -1,27 +1,59 @@
 use anyhow::Context;
 use event_listener::Event;
-use rusqlite::{params, OptionalExtension};
+use fs2::FileExt;
+use novasmt::ContentAddrStore;
+use rusqlite::{
+    backup::{Backup, StepResult},
+    params, OpenFlags, OptionalExtension,
+};
 use smol::channel::{Receiver, Sender};
 use std::{
     ops::{Deref, DerefMut},
-    path::PathBuf,
-    sync::Arc,
-    time::Instant,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use stdcode::StdcodeSerializeExt;
 use tap::Tap;
 use tip911_stakeset::StakeSet;
 use tmelcrypt::HashVal;
 
+use arc_swap::ArcSwap;
 use moka::sync::Cache;
 use parking_lot::RwLock;
 
 use melstf::{GenesisConfig, SealedState};
-use melstructs::{Block, BlockHeight, CoinValue, ConsensusProof, NetID, StakeDoc, TxHash, TxKind};
+use melstructs::{
+    Block, BlockHeight, CoinValue, ConsensusProof, Header, StakeDoc, Transaction, TxHash,
+    STAKE_EPOCH,
+};
 
 use crate::autoretry::autoretry;
 
-use super::{mempool::Mempool, MeshaCas};
+use super::{
+    copy_reachable, decode, for_each_reachable, mempool::Mempool, migrate, node_hash,
+    schema_version, ForestBackend, ForestStore, ReadStorage, SmtImport, SnapshotMeta,
+    SnapshotReader, SnapshotWriter, StorageError, StorageResultExt, SCHEMA_VERSION,
+};
+
+/// Newly prunable heights needed before another pruning pass.
+const PRUNE_INTERVAL: u64 = 5000;
+
+/// Options for opening a [Storage].
+#[derive(Clone, Debug, Default)]
+pub struct StorageConfig {
+    /// If set, only the last this-many sealed states are kept.
+    pub prune_keep: Option<u64>,
+    /// Where a new database keeps its SMT forest. `None` accepts an existing one as is.
+    pub forest_backend: Option<ForestBackend>,
+    /// Memory budget, in bytes, of the SMT node cache. Zero disables it.
+    pub forest_cache_bytes: u64,
+    /// How long mempool transactions are journaled across restarts. Zero disables it, leaving any existing journal untouched.
+    pub mempool_expiry: Duration,
+}
 
 /// Storage encapsulates all storage used by a Mel full node (replica or staker).
 #[derive(Clone)]
@@ -29,19 +61,37 @@ pub struct Storage {
     send_pool: Sender<rusqlite::Connection>,
     recv_pool: Receiver<rusqlite::Connection>,
     old_cache: Arc<Cache<BlockHeight, Block>>,
-    forest: Arc<novasmt::Database<MeshaCas>>,
+    /// Stakesets at the start of each epoch, also kept in `stakeset_epochs`.
+    epoch_stakes: Arc<Cache<u64, StakeSet>>,
+    forest: Arc<novasmt::Database<ForestStore>>,
 
     genesis: GenesisConfig,
 
     mempool: Arc<RwLock<Mempool>>,
 
+    /// The highest sealed state, kept up to date by whatever changes the highest block.
+    tip: Arc<ArcSwap<SealedState<ForestStore>>>,
+
     /// A notifier for a new block happening.
     new_block_notify: Arc<Event>,
 
     /// SQLite path
     sqlite_path: PathBuf,
 
+    /// How many sealed states to keep, if pruning.
+    prune_keep: Option<u64>,
+    /// Lowest height that hasn't been pruned.
+    pruned_below: Arc<AtomicU64>,
+    /// How long journaled mempool transactions are kept.
+    mempool_expiry: Duration,
+
     lock: Arc<smol::lock::Mutex<()>>,
+    /// Held while pruning, and by anything that must not overlap with it.
+    prune_lock: Arc<smol::lock::Mutex<()>>,
+    /// Exclusive lock on the database folder, released once every clone is dropped.
+    _dir_lock: Option<Arc<std::fs::File>>,
+    /// Whether this was opened with [Storage::open_readonly].
+    pub(super) read_only: bool,
 }
 
 impl Storage {
@@ -61,128 +111,363 @@ impl Storage {
     }
 
     /// Opens a NodeStorage, given a meshanina and boringdb database.
-    pub async fn open(mut db_folder: PathBuf, genesis: GenesisConfig) -> anyhow::Result<Self> {
-        let genesis_id = tmelcrypt::hash_single(stdcode::serialize(&genesis).unwrap());
-        db_folder.push(format!("{}/", hex::encode(genesis_id.0)));
-        std::fs::create_dir_all(&db_folder).context("cannot make folder")?;
+    pub async fn open(
+        db_folder: PathBuf,
+        genesis: GenesisConfig,
+        config: StorageConfig,
+    ) -> anyhow::Result<Self> {
+        Self::open_inner(db_folder, genesis, config, false).await
+    }
+
+    /// Opens an existing database read-only, without locking it.
+    pub async fn open_readonly(
+        db_folder: PathBuf,
+        genesis: GenesisConfig,
+    ) -> anyhow::Result<ReadStorage> {
+        let inner = Self::open_inner(db_folder, genesis, Default::default(), true).await?;
+        Ok(ReadStorage::new(inner))
+    }
+
+    /// Migrates just the sqlite schema, returning the version it was at before.
+    pub fn migrate_schema(db_folder: PathBuf, genesis: &GenesisConfig) -> anyhow::Result<u32> {
+        let db_folder = genesis_folder(db_folder, genesis);
+        let sqlite_path = db_folder.join("storage.db");
+        if !sqlite_path.exists() {
+            anyhow::bail!("no database at {}", db_folder.display());
+        }
+        let _dir_lock = lock_folder(&db_folder)?;
+        let mut conn = rusqlite::Connection::open(&sqlite_path).context("cannot open sqlite")?;
+        migrate(&mut conn).context("cannot migrate sqlite schema")
+    }
+
+    async fn open_inner(
+        db_folder: PathBuf,
+        genesis: GenesisConfig,
+        config: StorageConfig,
+        read_only: bool,
+    ) -> anyhow::Result<Self> {
+        let db_folder = genesis_folder(db_folder, &genesis);
+        let dir_lock = if read_only {
+            None
+        } else {
+            std::fs::create_dir_all(&db_folder).context("cannot make folder")?;
+            Some(Arc::new(lock_folder(&db_folder)?))
+        };
         let sqlite_path = db_folder.clone().tap_mut(|path| path.push("storage.db"));
-        let mesha_path = db_folder.clone().tap_mut(|path| path.push("merkle.db"));
+        let open_sqlite = || {
+            if read_only {
+                rusqlite::Connection::open_with_flags(
+                    &sqlite_path,
+                    OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+                )
+            } else {
+                rusqlite::Connection::open(&sqlite_path)
+            }
+        };
         log::debug!("about to sqlite");
-        let conn = rusqlite::Connection::open(&sqlite_path).context("cannot make sqlite")?;
-        conn.execute("create table if not exists history (height primary key not null, header not null, block not null)", params![])?;
-        conn.execute("create table if not exists consensus_proofs (height primary key not null, proof not null)", params![])?;
-        conn.execute(
-            "create table if not exists stakes (txhash primary key not null, height not null, stake_doc not null)",
-            params![],
-        )?;
-        conn.execute(
-            "create table if not exists misc (key primary key not null, value not null)",
-            params![],
-        )?;
-
+        let mut conn = open_sqlite().context("cannot make sqlite")?;
+        if read_only {
+            let version = schema_version(&conn)?;
+            if version != SCHEMA_VERSION {
+                anyhow::bail!(
+                    "database is at schema version {}, but this melnode reads version {}",
+                    version,
+                    SCHEMA_VERSION
+                );
+            }
+        } else {
+            migrate(&mut conn).context("cannot migrate sqlite schema")?;
+        }
         log::debug!("sqlite initted");
 
+        let pruned_below: Option<u64> = conn
+            .query_row(
+                "select value from misc where key = 'pruned_below'",
+                params![],
+                |r| r.get(0),
+            )
+            .optional()?;
+
+        let forest_backend = forest_backend(&conn, config.forest_backend)?;
+
         // initialize the stakes
-        for (txhash, stake) in genesis.stakes.iter() {
+        if !read_only {
             conn.execute(
-                "insert into stakes values ($1, $2, $3) on conflict do nothing",
-                params![txhash.to_string(), 0, stake.stdcode()],
+                "insert into misc (key, value) values ('forest_backend', $1) on conflict(key) do nothing",
+                params![forest_backend.to_string()],
             )?;
+            for (txhash, stake) in genesis.stakes.iter() {
+                conn.execute(
+                    "insert into stakes values ($1, $2, $3) on conflict do nothing",
+                    params![txhash.to_string(), 0, stake.stdcode()],
+                )?;
+            }
         }
 
         let (send_pool, recv_pool) = smol::channel::unbounded();
         for _ in 0..16 {
-            let conn = rusqlite::Connection::open(&sqlite_path)?;
-            conn.query_row("pragma journal_mode=WAL", params![], |_| Ok(()))?;
-            conn.execute("pragma synchronous=normal", params![])?;
+            let conn = open_sqlite()?;
+            if !read_only {
+                conn.query_row("pragma journal_mode=WAL", params![], |_| Ok(()))?;
+                conn.execute("pragma synchronous=normal", params![])?;
+            }
             send_pool.send(conn).await.unwrap();
         }
 
-        log::debug!("about to mesha");
-        let forest = novasmt::Database::new(MeshaCas::new(
-            meshanina::Mapping::open(&mesha_path).context("cannot open mesha")?,
-        ));
+        log::debug!("about to open the {} forest", forest_backend);
+        let forest = novasmt::Database::new(
+            if read_only {
+                forest_backend.open_readonly(&db_folder)?
+            } else {
+                forest_backend.open(&db_folder)?
+            }
+            .with_cache(config.forest_cache_bytes),
+        );
         let mempool = Arc::new(Mempool::new(genesis.clone().realize(&forest)).into());
-        Ok(Self {
+        let genesis_state = genesis.clone().realize(&forest).seal(None);
+        let storage = Self {
             send_pool,
             recv_pool,
             old_cache: Arc::new(Cache::new(1000)),
+            epoch_stakes: Arc::new(Cache::new(64)),
             forest: Arc::new(forest),
 
             genesis,
 
             new_block_notify: Arc::new(Event::new()),
             mempool,
+            tip: Arc::new(ArcSwap::from_pointee(genesis_state)),
             sqlite_path,
 
+            prune_keep: config.prune_keep,
+            pruned_below: Arc::new(AtomicU64::new(pruned_below.unwrap_or_default())),
+            mempool_expiry: config.mempool_expiry,
+
             lock: Default::default(),
+            prune_lock: Default::default(),
+            _dir_lock: dir_lock,
+            read_only,
+        };
+        if read_only {
+            storage.reload_tip().await?;
+            return Ok(storage);
+        }
+        storage.repair_tip().await?;
+        storage.reload_tip().await?;
+        storage.reload_mempool().await?;
+        Ok(storage)
+    }
+
+    /// Reloads the mempool from its journal, dropping what expired or no longer applies.
+    async fn reload_mempool(&self) -> anyhow::Result<()> {
+        let tip = self.highest_state().await?;
+        self.mempool_mut().rebase(tip);
+        let Some(cutoff) = journal_cutoff(self.mempool_expiry) else {
+            return Ok(());
+        };
+        let journaled: Vec<(String, Vec<u8>)> = {
+            let conn = self.recv_pool.recv().await?;
+            let send_pool = self.send_pool.clone();
+            smol::unblock(move || {
+                let conn = scopeguard::guard(conn, |conn| send_pool.try_send(conn).unwrap());
+                conn.execute(
+                    "delete from mempool_journal where received < $1",
+                    params![cutoff],
+                )?;
+                let mut stmt =
+                    conn.prepare("select txhash, tx from mempool_journal order by rowid")?;
+                let rows = stmt
+                    .query_map(params![], |r| Ok((r.get(0)?, r.get(1)?)))?
+                    .collect::<Result<Vec<_>, _>>()?;
+                anyhow::Ok(rows)
+            })
+            .await?
+        };
+        if journaled.is_empty() {
+            return Ok(());
+        }
+        let total = journaled.len();
+        let mut stale = vec![];
+        {
+            let mut mempool = self.mempool_mut();
+            for (txhash, tx) in journaled {
+                let res = decode::<Transaction>(&tx)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|tx| mempool.apply_transaction(&tx));
+                if let Err(err) = res {
+                    log::debug!("not reloading mempool tx {}: {:?}", txhash, err);
+                    stale.push(txhash);
+                }
+            }
+        }
+        log::info!(
+            "reloaded {} of {} journaled mempool txx",
+            total - stale.len(),
+            total
+        );
+        let conn = self.recv_pool.recv().await?;
+        let send_pool = self.send_pool.clone();
+        smol::unblock(move || {
+            let mut conn = scopeguard::guard(conn, |conn| send_pool.try_send(conn).unwrap());
+            let conn = conn.transaction()?;
+            for txhash in stale {
+                conn.execute(
+                    "delete from mempool_journal where txhash = $1",
+                    params![txhash],
+                )?;
+            }
+            conn.commit()?;
+            anyhow::Ok(())
         })
+        .await
+    }
+
+    /// Journals a transaction accepted into the mempool, if the journal is enabled.
+    pub async fn journal_tx(&self, tx: &Transaction) -> Result<(), StorageError> {
+        if self.mempool_expiry.is_zero() || self.read_only {
+            return Ok(());
+        }
+        let received = unix_time();
+        autoretry(|| async {
+            let conn = self.recv_pool.recv().await.map_err(StorageError::io)?;
+            let send_pool = self.send_pool.clone();
+            let tx = tx.clone();
+            smol::unblock(move || {
+                let conn = scopeguard::guard(conn, |conn| send_pool.try_send(conn).unwrap());
+                conn.execute(
+                    "insert into mempool_journal (txhash, tx, received) values ($1, $2, $3) on conflict do nothing",
+                    params![tx.hash_nosigs().to_string(), tx.stdcode(), received],
+                )?;
+                Ok(())
+            })
+            .await
+        })
+        .await
+    }
+
+    /// Truncates back to the highest state whose SMT roots are all in the forest.
+    async fn repair_tip(&self) -> anyhow::Result<()> {
+        let tip = self.stored_tip_height().await?;
+        let resolvable = {
+            let conn = self.recv_pool.recv().await?;
+            let send_pool = self.send_pool.clone();
+            let forest = self.forest.clone();
+            smol::unblock(move || {
+                let conn = scopeguard::guard(conn, |conn| send_pool.try_send(conn).unwrap());
+                let mut stmt = conn.prepare("select header from history order by height desc")?;
+                let mut rows = stmt.query(params![])?;
+                while let Some(row) = rows.next()? {
+                    let header: Header = decode(&row.get::<_, Vec<u8>>(0)?)?;
+                    if roots_resolve(forest.storage(), &header) {
+                        return anyhow::Ok(Some(header.height));
+                    }
+                }
+                anyhow::Ok(None)
+            })
+            .await?
+        };
+        // the genesis state is realized again on every open, so it always resolves
+        let height = resolvable.unwrap_or_default();
+        if height == tip {
+            return Ok(());
+        }
+        log::warn!(
+            "SMT roots of heights {}..={} are missing from the SMT forest, rolling back to {}",
+            height + 1.into(),
+            tip,
+            height
+        );
+        self.truncate_to(height)
+            .await
+            .context("cannot repair storage")?;
+        Ok(())
     }
 
     /// Obtain the highest state.
-    pub async fn highest_state(&self) -> SealedState<MeshaCas> {
-        // TODO this may be a bit stale
-        let height = self.highest_height().await;
-        if height.0 > 0 {
-            self.get_state(height).await.expect("highest not available")
+    pub async fn highest_state(&self) -> Result<SealedState<ForestStore>, StorageError> {
+        Ok(SealedState::clone(&self.tip.load()))
+    }
+
+    /// The header of the highest state, which is always at hand.
+    pub fn highest_header(&self) -> Header {
+        self.tip.load().header()
+    }
+
+    /// Obtain the highest height.
+    pub async fn highest_height(&self) -> Result<BlockHeight, StorageError> {
+        Ok(self.tip.load().header().height)
+    }
+
+    /// Reloads the cached tip from sqlite. Call with the storage lock held.
+    async fn reload_tip(&self) -> Result<(), StorageError> {
+        let height = self.stored_tip_height().await?;
+        let state = if height.0 > 0 {
+            self.load_state(height).await?
         } else {
             self.genesis.clone().realize(self.forest()).seal(None)
-        }
+        };
+        self.tip.store(Arc::new(state));
+        Ok(())
     }
 
-    /// Obtain the highest height.
-    pub async fn highest_height(&self) -> BlockHeight {
+    /// Reads the highest stored height from sqlite.
+    pub(super) async fn stored_tip_height(&self) -> Result<BlockHeight, StorageError> {
         autoretry(|| async {
-            let conn = self.recv_pool.recv().await?;
+            let conn = self.recv_pool.recv().await.map_err(StorageError::io)?;
             let send_pool = self.send_pool.clone();
             smol::unblock(move || {
                 let conn = scopeguard::guard(conn, |conn| send_pool.try_send(conn).unwrap());
                 let val: Option<u64> =
                     conn.query_row("select max(height) from history", params![], |r| r.get(0))?;
-                anyhow::Ok(val.map(BlockHeight))
+                Ok(val.map(BlockHeight).unwrap_or_default())
             })
             .await
         })
         .await
-        .unwrap_or_default()
+    }
+
+    /// Obtain the lowest height that hasn't been pruned.
+    pub fn pruned_below(&self) -> BlockHeight {
+        BlockHeight(self.pruned_below.load(Ordering::SeqCst))
+    }
+
+    /// Checks whether the given height has been pruned.
+    pub fn is_pruned(&self, height: BlockHeight) -> bool {
+        height < self.pruned_below()
     }
 
     /// Waits until a certain height is available, then returns it.
-    pub async fn get_state_or_wait(&self, height: BlockHeight) -> SealedState<MeshaCas> {
+    pub async fn get_state_or_wait(
+        &self,
+        height: BlockHeight,
+    ) -> Result<SealedState<ForestStore>, StorageError> {
         loop {
             let notify = self.new_block_notify.listen();
-            match self.get_state(height).await {
-                Some(val) => return val,
-                _ => notify.await,
+            if let Some(val) = self.get_state(height).await.optional()? {
+                return Ok(val);
             }
+            notify.await
         }
     }
 
     /// Reconstruct the stakeset at a given height.
-    async fn get_stakeset(&self, height: BlockHeight) -> StakeSet {
+    pub async fn get_stakeset(&self, height: BlockHeight) -> Result<StakeSet, StorageError> {
+        // the rows below the tip's epoch are final, so only those epochs' stakesets may be saved
+        let final_epoch = (self.highest_height().await?.0 + 1) / STAKE_EPOCH;
         autoretry(|| async {
-            let conn = self.recv_pool.recv().await?;
+            let conn = self.recv_pool.recv().await.map_err(StorageError::io)?;
             let send_pool = self.send_pool.clone();
             let genesis = self.genesis.clone();
+            let epoch_stakes = self.epoch_stakes.clone();
+            let save = !self.read_only;
             smol::unblock(move || {
                 let conn = scopeguard::guard(conn, |conn| send_pool.try_send(conn).unwrap());
-                let mut stmt = conn
-                    .prepare("select txhash, height, stake_doc from stakes where height <= $1")?;
-                let mut stakes = StakeSet::new(vec![].into_iter());
-                // TODO this is dumb!
-                for (txhash, stake) in genesis.stakes {
-                    stakes.add_stake(txhash, stake);
-                }
-                for row in stmt.query_map(params![height.0], |row| {
-                    Ok((row.get(0)?, row.get(1)?, row.get(2)?))
-                })? {
-                    let row: (String, u64, Vec<u8>) = row?;
-                    let t: TxHash = row.0.parse()?;
-                    let sd: StakeDoc = stdcode::deserialize(&row.2)?;
-                    stakes.add_stake(t, sd);
-                }
-                stakes.unlock_old(height.epoch());
-                anyhow::Ok(stakes)
+                let epoch = height.epoch();
+                let mut stakes =
+                    build_epoch_stakes(&conn, &genesis, &epoch_stakes, epoch, final_epoch, save)?;
+                add_stake_rows(&mut stakes, &conn, epoch * STAKE_EPOCH, height.0)?;
+                stakes.unlock_old(epoch);
+                Ok(stakes)
             })
             .await
         })
@@ -190,31 +475,33 @@ impl Storage {
     }
 
     /// Obtain just one particular Block.
-    pub async fn get_block(&self, height: BlockHeight) -> Option<Block> {
+    pub async fn get_block(&self, height: BlockHeight) -> Result<Block, StorageError> {
+        if self.is_pruned(height) {
+            return Err(StorageError::Pruned(height));
+        }
         autoretry(|| async {
             if let Some(val) = self.old_cache.get(&height) {
-                return anyhow::Ok(Some(val));
+                return Ok(val);
             }
-            let conn = self.recv_pool.recv().await?;
+            let conn = self.recv_pool.recv().await.map_err(StorageError::io)?;
             let send_pool = self.send_pool.clone();
             let res = smol::unblock(move || {
                 let conn = scopeguard::guard(conn, |conn| send_pool.try_send(conn).unwrap());
-                let block_blob: Option<Vec<u8>> = conn
+                let block_blob: Option<(u8, Vec<u8>)> = conn
                     .query_row(
-                        "select block from history where height = $1",
+                        "select block_format, block from history where height = $1",
                         params![height.0],
-                        |row| row.get(0),
+                        |row| Ok((row.get(0)?, row.get(1)?)),
                     )
                     .optional()?;
-                if let Some(block_blob) = block_blob {
-                    let block: Block = stdcode::deserialize(&block_blob)?;
-                    Ok(Some(block))
+                if let Some((format, block_blob)) = block_blob {
+                    decode::<Block>(&block_stdcode(format, block_blob)?)
                 } else {
-                    Ok(None)
+                    Err(StorageError::NotFound(format!("block {}", height)))
                 }
             })
             .await;
-            if let Ok(Some(res)) = &res {
+            if let Ok(res) = &res {
                 self.old_cache.insert(height, res.clone());
             }
             res
@@ -222,21 +509,133 @@ impl Storage {
         .await
     }
 
+    /// Reads consecutive stdcode-encoded blocks and proofs, up to about `size_limit` bytes.
+    pub async fn get_raw_blocks(
+        &self,
+        height: BlockHeight,
+        size_limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>, StorageError> {
+        if self.is_pruned(height) {
+            return Err(StorageError::Pruned(height));
+        }
+        autoretry(|| async {
+            let conn = self.recv_pool.recv().await.map_err(StorageError::io)?;
+            let send_pool = self.send_pool.clone();
+            smol::unblock(move || {
+                let conn = scopeguard::guard(conn, |conn| send_pool.try_send(conn).unwrap());
+                let mut stmt = conn.prepare_cached(
+                    "select history.height, block_format, block, proof from history join consensus_proofs on consensus_proofs.height = history.height where history.height >= $1 order by history.height",
+                )?;
+                let mut rows = stmt.query(params![height.0])?;
+                let mut blocks: Vec<(Vec<u8>, Vec<u8>)> = vec![];
+                let mut total = 0;
+                while let Some(row) = rows.next()? {
+                    if row.get::<_, u64>(0)? != height.0 + blocks.len() as u64 {
+                        break;
+                    }
+                    let block = block_stdcode(row.get(1)?, row.get(2)?)?;
+                    let proof: Vec<u8> = row.get(3)?;
+                    total += block.len() + proof.len();
+                    if total > size_limit && !blocks.is_empty() {
+                        break;
+                    }
+                    blocks.push((block, proof));
+                }
+                if blocks.is_empty() {
+                    return Err(StorageError::NotFound(format!("block {}", height)));
+                }
+                Ok(blocks)
+            })
+            .await
+        })
+        .await
+    }
+
+    /// Lists every height in the history table, in order.
+    pub(super) async fn stored_heights(&self) -> Result<Vec<BlockHeight>, StorageError> {
+        let conn = self.recv_pool.recv().await.map_err(StorageError::io)?;
+        let send_pool = self.send_pool.clone();
+        smol::unblock(move || {
+            let conn = scopeguard::guard(conn, |conn| send_pool.try_send(conn).unwrap());
+            let mut stmt = conn.prepare("select height from history order by height")?;
+            let heights = stmt
+                .query_map(params![], |r| r.get(0).map(BlockHeight))?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(heights)
+        })
+        .await
+    }
+
+    /// Reads the raw blobs of a height's row, without decoding or retrying.
+    pub(super) async fn raw_row(
+        &self,
+        height: BlockHeight,
+    ) -> Result<(Vec<u8>, u8, Vec<u8>, Option<Vec<u8>>), StorageError> {
+        let conn = self.recv_pool.recv().await.map_err(StorageError::io)?;
+        let send_pool = self.send_pool.clone();
+        smol::unblock(move || {
+            let conn = scopeguard::guard(conn, |conn| send_pool.try_send(conn).unwrap());
+            let (header, format, block) = conn.query_row(
+                "select header, block_format, block from history where height = $1",
+                params![height.0],
+                |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+            )?;
+            let proof = conn
+                .query_row(
+                    "select proof from consensus_proofs where height = $1",
+                    params![height.0],
+                    |r| r.get(0),
+                )
+                .optional()?;
+            Ok((header, format, block, proof))
+        })
+        .await
+    }
+
+    /// The header of the genesis state, which block 1 builds on.
+    pub(super) fn genesis_header(&self) -> Header {
+        self.genesis
+            .clone()
+            .realize(self.forest())
+            .seal(None)
+            .header()
+    }
+
     /// Obtain a historical SealedState.
-    pub async fn get_state(&self, height: BlockHeight) -> Option<SealedState<MeshaCas>> {
+    pub async fn get_state(
+        &self,
+        height: BlockHeight,
+    ) -> Result<SealedState<ForestStore>, StorageError> {
+        let tip = self.tip.load();
+        if tip.header().height == height && height.0 > 0 {
+            return Ok(SealedState::clone(&tip));
+        }
+        self.load_state(height).await
+    }
+
+    /// Reconstructs the state at a height from sqlite, bypassing the cached tip.
+    async fn load_state(
+        &self,
+        height: BlockHeight,
+    ) -> Result<SealedState<ForestStore>, StorageError> {
         let block: Block = self.get_block(height).await?;
-        let stakeset = self.get_stakeset(height).await;
-        assert_eq!(
-            HashVal(stakeset.pre_tip911().root_hash()),
-            block.header.stakes_hash
-        );
-        Some(SealedState::from_block(&block, &stakeset, &self.forest))
+        let stakeset = self.get_stakeset(height).await?;
+        if HashVal(stakeset.pre_tip911().root_hash()) != block.header.stakes_hash {
+            return Err(StorageError::Corrupt(format!(
+                "stakes at height {} do not match the header",
+                height
+            )));
+        }
+        Ok(SealedState::from_block(&block, &stakeset, &self.forest))
     }
 
     /// Obtain a historical ConsensusProof.
-    pub async fn get_consensus(&self, height: BlockHeight) -> Option<ConsensusProof> {
+    pub async fn get_consensus(&self, height: BlockHeight) -> Result<ConsensusProof, StorageError> {
+        if self.is_pruned(height) {
+            return Err(StorageError::Pruned(height));
+        }
         autoretry(|| async {
-            let conn = self.recv_pool.recv().await?;
+            let conn = self.recv_pool.recv().await.map_err(StorageError::io)?;
             let send_pool = self.send_pool.clone();
             smol::unblock(move || {
                 let conn = scopeguard::guard(conn, |conn| send_pool.try_send(conn).unwrap());
@@ -248,9 +647,12 @@ impl Storage {
                     )
                     .optional()?;
                 if let Some(vec) = vec {
-                    anyhow::Ok(Some(stdcode::deserialize(&vec)?))
+                    decode(&vec)
                 } else {
-                    Ok(None)
+                    Err(StorageError::NotFound(format!(
+                        "consensus proof {}",
+                        height
+                    )))
                 }
             })
             .await
@@ -258,103 +660,935 @@ impl Storage {
         .await
     }
 
+    /// Looks up a confirmed transaction, with its height and index in the block.
+    pub async fn get_transaction(
+        &self,
+        txhash: TxHash,
+    ) -> Result<(Transaction, BlockHeight, u32), StorageError> {
+        let (height, index) = autoretry(|| async {
+            let conn = self.recv_pool.recv().await.map_err(StorageError::io)?;
+            let send_pool = self.send_pool.clone();
+            smol::unblock(move || {
+                let conn = scopeguard::guard(conn, |conn| send_pool.try_send(conn).unwrap());
+                let loc: Option<(u64, u32)> = conn
+                    .query_row(
+                        "select height, idx from transactions where txhash = $1",
+                        params![txhash.to_string()],
+                        |r| Ok((r.get(0)?, r.get(1)?)),
+                    )
+                    .optional()?;
+                loc.map(|(height, index)| (BlockHeight(height), index))
+                    .ok_or_else(|| StorageError::NotFound(format!("transaction {}", txhash)))
+            })
+            .await
+        })
+        .await?;
+        let block = self.get_block(height).await?;
+        let tx = sorted_transactions(&block)
+            .into_iter()
+            .nth(index as usize)
+            .filter(|tx| tx.hash_nosigs() == txhash)
+            .ok_or_else(|| {
+                StorageError::Corrupt(format!(
+                    "transaction {} is not where the index says it is",
+                    txhash
+                ))
+            })?;
+        Ok((tx, height, index))
+    }
+
+    /// Looks up a block, along with its consensus proof, by the hash of its header.
+    pub async fn get_block_by_hash(
+        &self,
+        hash: HashVal,
+    ) -> Result<(Block, ConsensusProof), StorageError> {
+        let height = autoretry(|| async {
+            let conn = self.recv_pool.recv().await.map_err(StorageError::io)?;
+            let send_pool = self.send_pool.clone();
+            smol::unblock(move || {
+                let conn = scopeguard::guard(conn, |conn| send_pool.try_send(conn).unwrap());
+                let height: Option<u64> = conn
+                    .query_row(
+                        "select height from block_hashes where hash = $1",
+                        params![hash.to_string()],
+                        |r| r.get(0),
+                    )
+                    .optional()?;
+                height
+                    .map(BlockHeight)
+                    .ok_or_else(|| StorageError::NotFound(format!("block with hash {}", hash)))
+            })
+            .await
+        })
+        .await?;
+        let block = self.get_block(height).await?;
+        if block.header.hash() != hash {
+            return Err(StorageError::Corrupt(format!(
+                "block {} does not have the indexed hash {}",
+                height, hash
+            )));
+        }
+        let proof = self.get_consensus(height).await?;
+        Ok((block, proof))
+    }
+
     /// Consumes a block, applying it to the current state.
-    pub async fn apply_block(&self, blk: Block, cproof: ConsensusProof) -> anyhow::Result<()> {
+    pub async fn apply_block(
+        &self,
+        blk: Block,
+        cproof: ConsensusProof,
+    ) -> Result<(), StorageError> {
+        self.apply_blocks(vec![(blk, cproof)]).await
+    }
+
+    /// Applies a batch of consecutive blocks, keeping those before the first invalid one.
+    pub async fn apply_blocks(
+        &self,
+        blocks: Vec<(Block, ConsensusProof)>,
+    ) -> Result<(), StorageError> {
         let _guard = self.lock.lock().await;
-        if blk.header.height.0 == 531 {
-            eprintln!("APPLY BLOCK: {:#?}", blk);
+        let start = Instant::now();
+        let mut state = self.highest_state().await?;
+        let mut valid = Vec::with_capacity(blocks.len());
+        let mut error = None;
+        for (blk, cproof) in blocks {
+            let next = (|| {
+                if blk.header.height != state.header().height + 1.into() {
+                    anyhow::bail!(
+                        "cannot apply block {} to height {}",
+                        blk.header.height,
+                        state.header().height
+                    );
+                }
+                check_cproof(&state.raw_stakes(), &blk.header, &cproof)?;
+                Ok(state.apply_block(&blk)?)
+            })();
+            match next {
+                Ok(next) => {
+                    let new_stakes = new_stakes(&state, &next);
+                    state = next;
+                    valid.push((blk, cproof, new_stakes));
+                }
+                Err(err) => {
+                    error = Some(StorageError::bad_request(err));
+                    break;
+                }
+            }
         }
-        let highest_state = self.highest_state().await;
-        let header = blk.header;
-        if header.height != highest_state.header().height + 1.into() {
-            anyhow::bail!(
-                "cannot apply block {} to height {}",
-                header.height,
-                highest_state.header().height
+        if valid.is_empty() {
+            return Err(
+                error.unwrap_or_else(|| StorageError::BadRequest("no blocks to apply".into()))
             );
         }
+        // we flush the merkle stuff first, because the sqlite points to merkle
+        self.forest.storage().flush()?;
+        let apply_time = start.elapsed();
+        let start = Instant::now();
 
-        // Check the consensus proof
-        let mut total_votes = CoinValue(0);
-        let mut present_votes = CoinValue(0);
-        for stake_doc_bytes in highest_state.raw_stakes().pre_tip911().iter() {
-            let stake_doc: StakeDoc = stdcode::deserialize(&stake_doc_bytes.1)?;
-            if blk.header.height.epoch() >= stake_doc.e_start
-                && blk.header.height.epoch() < stake_doc.e_post_end
-            {
-                total_votes += stake_doc.syms_staked;
-                if let Some(v) = cproof.get(&stake_doc.pubkey) {
-                    if stake_doc.pubkey.verify(&blk.header.hash(), v) {
-                        present_votes += total_votes;
-                    }
+        // now transactionally save to sqlite
+        let count = valid.len();
+        let cutoff = journal_cutoff(self.mempool_expiry);
+        {
+            let conn = self.recv_pool.recv().await.map_err(StorageError::io)?;
+            let send_pool = self.send_pool.clone();
+            smol::unblock(move || {
+                let mut conn = scopeguard::guard(conn, |conn| send_pool.try_send(conn).unwrap());
+                let conn = conn.transaction()?;
+                for (blk, cproof, new_stakes) in valid {
+                    insert_block(&conn, &blk, &cproof, &new_stakes)?;
                 }
-            }
+                if let Some(cutoff) = cutoff {
+                    conn.execute(
+                        "delete from mempool_journal where received < $1",
+                        params![cutoff],
+                    )?;
+                }
+                conn.commit()?;
+                Ok::<_, StorageError>(())
+            })
+            .await?
         }
-        if present_votes.0 <= 2 * total_votes.0 / 3 {
-            anyhow::bail!(
-                "rejecting putative block {} due to insufficient votes ({}/{})",
-                blk.header.height,
-                present_votes,
-                total_votes
-            )
+        log::debug!(
+            "applied {} blocks up to {} / {} in {:.2}ms (history insertion {:.2}ms, SMT cache {:?})",
+            count,
+            state.header().height,
+            state.header().hash(),
+            apply_time.as_secs_f64() * 1000.0,
+            start.elapsed().as_secs_f64() * 1000.0,
+            self.forest.storage().cache_stats()
+        );
+        // the in-memory state matches what's now stored, since applying a block checks the resulting header, stakes hash included
+        self.tip.store(Arc::new(state.clone()));
+        let height = state.header().height;
+        self.mempool_mut().rebase(state);
+        self.new_block_notify.notify(usize::MAX);
+        if let Some(keep) = self.prune_keep {
+            self.spawn_prune(height, keep);
         }
 
-        let start = Instant::now();
-        let new_state = highest_state.apply_block(&blk)?;
-        // we flush the merkle stuff first, because the sqlite points to merkle
-        self.forest.storage().flush();
-        let apply_time = start.elapsed();
+        match error {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Rolls back to the given height, returning how many blocks were removed.
+    pub async fn truncate_to(&self, height: BlockHeight) -> Result<u64, StorageError> {
+        let _prune_guard = self.prune_lock.lock().await;
+        let _guard = self.lock.lock().await;
+        if self.is_pruned(height) {
+            return Err(StorageError::Pruned(height));
+        }
+        let removed = {
+            let conn = self.recv_pool.recv().await.map_err(StorageError::io)?;
+            let send_pool = self.send_pool.clone();
+            smol::unblock(move || {
+                let mut conn = scopeguard::guard(conn, |conn| send_pool.try_send(conn).unwrap());
+                let conn = conn.transaction()?;
+                let removed =
+                    conn.execute("delete from history where height > $1", params![height.0])?;
+                conn.execute(
+                    "delete from consensus_proofs where height > $1",
+                    params![height.0],
+                )?;
+                conn.execute("delete from stakes where height > $1", params![height.0])?;
+                conn.execute(
+                    "delete from transactions where height > $1",
+                    params![height.0],
+                )?;
+                conn.execute(
+                    "delete from block_hashes where height > $1",
+                    params![height.0],
+                )?;
+                conn.execute(
+                    "delete from stakeset_epochs where epoch * $1 > $2",
+                    params![STAKE_EPOCH, height.0 + 1],
+                )?;
+                conn.commit()?;
+                Ok::<_, StorageError>(removed as u64)
+            })
+            .await?
+        };
+        // cached blocks above the new tip no longer exist, and may come back different
+        self.old_cache.invalidate_all();
+        self.epoch_stakes.invalidate_all();
+        log::warn!(
+            "truncated storage to height {} ({} blocks removed)",
+            height,
+            removed
+        );
+
+        self.reload_tip().await?;
+        let next = self.highest_state().await?;
+        self.mempool_mut().rebase(next);
+        Ok(removed)
+    }
+
+    /// Starts a background pruning pass, if one is due and none is running.
+    fn spawn_prune(&self, tip: BlockHeight, keep: u64) {
+        let horizon = BlockHeight((tip.0 + 1).saturating_sub(keep));
+        if horizon.0 < self.pruned_below().0 + PRUNE_INTERVAL {
+            return;
+        }
+        let Some(guard) = self.prune_lock.try_lock_arc() else {
+            return;
+        };
+        let this = self.clone();
+        smolscale::spawn(async move {
+            if let Err(err) = this.prune(horizon).await {
+                log::warn!("cannot prune below {}: {:?}", horizon, err);
+            }
+            drop(guard);
+        })
+        .detach();
+    }
+
+    /// Prunes below the horizon, then compacts the forest. Needs the prune lock.
+    async fn prune(&self, horizon: BlockHeight) -> anyhow::Result<()> {
         let start = Instant::now();
 
-        // now transactionally save to sqlite
+        // we forget the old blocks first, because the sqlite points to merkle
         {
             let conn = self.recv_pool.recv().await?;
             let send_pool = self.send_pool.clone();
-            let _forest = self.forest.clone();
             smol::unblock(move || {
                 let mut conn = scopeguard::guard(conn, |conn| send_pool.try_send(conn).unwrap());
                 let conn = conn.transaction()?;
                 conn.execute(
-                    "insert into history (height, header, block) values ($1, $2, $3)",
-                    params![blk.header.height.0, blk.header.stdcode(), blk.stdcode()],
+                    "delete from history where height < $1",
+                    params![horizon.0],
                 )?;
-
                 conn.execute(
-                    "insert into consensus_proofs (height, proof) values ($1, $2)",
-                    params![blk.header.height.0, stdcode::serialize(&cproof).unwrap()],
+                    "delete from consensus_proofs where height < $1",
+                    params![horizon.0],
+                )?;
+                conn.execute(
+                    "delete from transactions where height < $1",
+                    params![horizon.0],
+                )?;
+                conn.execute(
+                    "delete from block_hashes where height < $1",
+                    params![horizon.0],
+                )?;
+                conn.execute(
+                    "insert into misc (key, value) values ('pruned_below', $1) on conflict(key) do update set value = excluded.value",
+                    params![horizon.0],
                 )?;
-
-                for txn in blk.transactions {
-                    if txn.kind == TxKind::Stake {
-                        if let Ok(doc) = stdcode::deserialize::<StakeDoc>(&txn.data) {
-                            // TODO BUG BUG this poorly replicates the validation logic. Make a method SealedState::new_stakes()
-                            if blk.header.height.0 >= 500000 || blk.header.network != NetID::Mainnet {
-                            conn.execute("insert into stakes (txhash, height, stake_doc) values ($1, $2, $3)", params![txn.hash_nosigs().to_string(), blk.header.height.0, doc.stdcode()])?;
-                            }
-                        }
-                    }
-                }
                 conn.commit()?;
                 anyhow::Ok(())
             })
             .await?
         }
-        log::debug!(
-            "applied block {} / {} in {:.2}ms (history insertion {:.2}ms)",
-            new_state.header().height,
-            new_state.header().hash(),
-            apply_time.as_secs_f64() * 1000.0,
-            start.elapsed().as_secs_f64() * 1000.0
+        self.pruned_below.store(horizon.0, Ordering::SeqCst);
+
+        // then we rebuild the forest out of only the nodes the remaining states can reach. Blocks stay out until inserts reach the compacted forest, so none is half-applied while the roots are read.
+        let guard = self.lock.lock_arc().await;
+        let (started_send, started_recv) = smol::channel::bounded(1);
+        let conn = self.recv_pool.recv().await?;
+        let send_pool = self.send_pool.clone();
+        let forest = self.forest.clone();
+        let copied = smol::unblock(move || {
+            let conn = scopeguard::guard(conn, |conn| send_pool.try_send(conn).unwrap());
+            forest.storage().compact(|| {
+                let _ = started_send.try_send(());
+                let mut roots = vec![];
+                let mut stmt = conn.prepare("select header from history")?;
+                for header in stmt.query_map(params![], |r| r.get::<_, Vec<u8>>(0))? {
+                    let header: Header = stdcode::deserialize(&header?)?;
+                    roots.extend([header.coins_hash, header.history_hash, header.pools_hash]);
+                }
+                Ok(roots.into_iter().map(|h| h.0).collect())
+            })
+        });
+        let _ = started_recv.recv().await;
+        drop(guard);
+        let copied = copied.await?;
+        // the mempool's provisional nodes only went into the old forest
+        let _guard = self.lock.lock().await;
+        let tip = self.highest_state().await?;
+        self.mempool_mut().rebase(tip);
+        log::info!(
+            "pruned history below {} ({} SMT nodes kept) in {:.2}s",
+            horizon,
+            copied,
+            start.elapsed().as_secs_f64()
         );
-        let next = self.highest_state().await;
+        Ok(())
+    }
+
+    /// Backs up a read-only storage. See [ReadStorage::backup].
+    pub(super) async fn backup(&self, dest: PathBuf) -> Result<BlockHeight, StorageError> {
+        let folder = dest.join(
+            self.sqlite_path
+                .parent()
+                .and_then(|p| p.file_name())
+                .ok_or_else(|| StorageError::BadRequest("database has no genesis folder".into()))?,
+        );
+        if folder.exists() {
+            return Err(StorageError::BadRequest(format!(
+                "{} already exists",
+                folder.display()
+            )));
+        }
+        std::fs::create_dir_all(&folder).map_err(StorageError::io)?;
+        let sqlite_path = self.sqlite_path.clone();
+        let forest = self.forest.clone();
+        smol::unblock(move || {
+            let src = rusqlite::Connection::open_with_flags(
+                &sqlite_path,
+                OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            )?;
+            let mut dst = rusqlite::Connection::open(folder.join("storage.db"))?;
+            {
+                let backup = Backup::new(&src, &mut dst)?;
+                // copying every page in one step reads from a single snapshot, which in WAL mode doesn't hold up the node
+                while backup.step(-1)? != StepResult::Done {
+                    std::thread::sleep(Duration::from_millis(100));
+                }
+            }
+            let header: Option<Vec<u8>> = dst
+                .query_row(
+                    "select header from history order by height desc limit 1",
+                    params![],
+                    |r| r.get(0),
+                )
+                .optional()?;
+
+            forest
+                .storage()
+                .copy_flushed(&folder)
+                .map_err(StorageError::io)?;
+            let Some(header) = header else {
+                return Ok(BlockHeight(0));
+            };
+            let header: Header = decode(&header)?;
+            let copy = forest
+                .storage()
+                .backend()
+                .open_readonly(&folder)
+                .map_err(StorageError::io)?;
+            if !roots_resolve(&copy, &header) {
+                return Err(StorageError::Busy(
+                    "the SMT forest was compacted during the backup, probably by pruning; try again"
+                        .into(),
+                ));
+            }
+            Ok(header.height)
+        })
+        .await
+    }
+
+    /// Moves the SMT forest into another backend, returning the number of nodes copied.
+    pub async fn migrate_forest(&self, to: ForestBackend) -> Result<u64, StorageError> {
+        let _prune_guard = self.prune_lock.lock().await;
+        let _guard = self.lock.lock().await;
+        let from = self.forest.storage().backend();
+        if to == from {
+            return Err(StorageError::BadRequest(format!(
+                "SMT forest is already kept in {}",
+                to
+            )));
+        }
+        if to == ForestBackend::Memory {
+            return Err(StorageError::BadRequest(
+                "cannot migrate to an in-memory forest, which would be lost on exit".into(),
+            ));
+        }
+        let conn = self.recv_pool.recv().await.map_err(StorageError::io)?;
+        let send_pool = self.send_pool.clone();
+        let forest = self.forest.clone();
+        let folder = self
+            .sqlite_path
+            .parent()
+            .ok_or_else(|| StorageError::BadRequest("database has no folder".into()))?
+            .to_owned();
+        // the tip is the genesis state, which isn't in history, if nothing has been applied yet
+        let tip = self.tip.load().header();
+        let start = Instant::now();
+        let copied = smol::unblock(move || {
+            let conn = scopeguard::guard(conn, |conn| send_pool.try_send(conn).unwrap());
+            let mut roots = vec![tip.coins_hash, tip.history_hash, tip.pools_hash];
+            {
+                let mut stmt = conn.prepare("select header from history")?;
+                for header in stmt.query_map(params![], |r| r.get::<_, Vec<u8>>(0))? {
+                    let header: Header = decode(&header?)?;
+                    roots.extend([header.coins_hash, header.history_hash, header.pools_hash]);
+                }
+            }
+            // whatever an interrupted migration left behind
+            to.remove(&folder).map_err(StorageError::io)?;
+            let dest = to.open(&folder).map_err(StorageError::io)?;
+            let copied = copy_reachable(forest.storage(), &dest, roots.into_iter().map(|h| h.0))
+                .map_err(StorageError::corrupt)?;
+            dest.flush()?;
+            conn.execute(
+                "insert into misc (key, value) values ('forest_backend', $1) on conflict(key) do update set value = excluded.value",
+                params![to.to_string()],
+            )?;
+            forest.storage().replace(dest);
+            Ok::<_, StorageError>(copied)
+        })
+        .await?;
+        log::info!(
+            "migrated SMT forest from {} to {} ({} nodes) in {:.2}s",
+            from,
+            to,
+            copied,
+            start.elapsed().as_secs_f64()
+        );
+        // the mempool's provisional nodes only ever went into the old forest
+        let tip = self.highest_state().await?;
+        self.mempool_mut().rebase(tip);
+        Ok(copied)
+    }
+
+    /// Exports the state at a height to a snapshot file, returning how many nodes it holds.
+    pub async fn export_snapshot(
+        &self,
+        height: BlockHeight,
+        path: PathBuf,
+    ) -> Result<u64, StorageError> {
+        let _guard = self.lock.lock().await;
+        let block = self.get_block(height).await?;
+        let cproof = self.get_consensus(height).await?;
+        let stakes = self
+            .get_stakeset(height)
+            .await?
+            .iter()
+            .map(|(txhash, stake)| (*txhash, *stake))
+            .collect();
+        let meta = SnapshotMeta {
+            block,
+            cproof,
+            stakes,
+        };
+        let forest = self.forest.clone();
+        smol::unblock(move || {
+            let file =
+                std::io::BufWriter::new(std::fs::File::create(&path).map_err(StorageError::io)?);
+            let mut writer = SnapshotWriter::new(file, &meta).map_err(StorageError::io)?;
+            let header = meta.block.header;
+            let count = for_each_reachable(
+                forest.storage(),
+                [
+                    header.coins_hash.0,
+                    header.history_hash.0,
+                    header.pools_hash.0,
+                ],
+                |hash, node| Ok(writer.write_node(hash, node)?),
+            )
+            .map_err(StorageError::io)?;
+            writer
+                .finish()
+                .and_then(|file| file.into_inner()?.sync_all())
+                .map_err(StorageError::io)?;
+            Ok(count)
+        })
+        .await
+    }
+
+    /// Imports a snapshot into an empty storage, checking it against a trusted header hash.
+    pub async fn import_snapshot(
+        &self,
+        path: PathBuf,
+        trusted: HashVal,
+    ) -> Result<BlockHeight, StorageError> {
+        if self.highest_height().await?.0 > 0 {
+            return Err(StorageError::BadRequest(
+                "snapshots can only be imported into an empty database".into(),
+            ));
+        }
+        let forest = self.forest.clone();
+        let meta = smol::unblock(move || {
+            let file = std::io::BufReader::new(std::fs::File::open(&path)?);
+            let (mut reader, meta) = SnapshotReader::new(file)?;
+            let header = meta.block.header;
+            if header.hash() != trusted {
+                anyhow::bail!(
+                    "snapshot header {} does not match the trusted hash {}",
+                    header.hash(),
+                    trusted
+                );
+            }
+            let mut import = SmtImport::new(
+                forest.storage(),
+                [
+                    header.coins_hash.0,
+                    header.history_hash.0,
+                    header.pools_hash.0,
+                ],
+            );
+            while let Some((hash, node)) = reader.next_node()? {
+                if node_hash(&node) != Some(hash) {
+                    anyhow::bail!("corrupt SMT node {} in snapshot", hex::encode(hash));
+                }
+                import.insert(node).context("bad SMT node in snapshot")?;
+            }
+            import.finish().context("snapshot is missing SMT nodes")?;
+            anyhow::Ok(meta)
+        })
+        .await
+        .map_err(StorageError::bad_request)?;
+        self.import_state(meta).await
+    }
+
+    /// Imports a sealed state whose nodes are already in the forest. The header must be trusted.
+    pub async fn import_state(&self, meta: SnapshotMeta) -> Result<BlockHeight, StorageError> {
+        let _guard = self.lock.lock().await;
+        if self.highest_height().await?.0 > 0 {
+            return Err(StorageError::BadRequest(
+                "states can only be imported into an empty database".into(),
+            ));
+        }
+        let header = meta.block.header;
+        if header.network != self.genesis.network {
+            return Err(StorageError::BadRequest(format!(
+                "state is for the wrong network {:?}",
+                header.network
+            )));
+        }
+        let forest = self.forest.clone();
+        let meta = smol::unblock(move || {
+            let stakes = StakeSet::new(meta.stakes.iter().cloned());
+            check_cproof(&stakes, &header, &meta.cproof)?;
+            // the state must be complete, and must reproduce the trusted header
+            for_each_reachable(
+                forest.storage(),
+                [
+                    header.coins_hash.0,
+                    header.history_hash.0,
+                    header.pools_hash.0,
+                ],
+                |_, _| Ok(()),
+            )
+            .context("state is missing SMT nodes")?;
+            if SealedState::from_block(&meta.block, &stakes, &forest).header() != header {
+                anyhow::bail!("state does not match its header");
+            }
+            anyhow::Ok(meta)
+        })
+        .await
+        .map_err(StorageError::bad_request)?;
+        let forest = self.forest.clone();
+        smol::unblock(move || forest.storage().flush()).await?;
+
+        let height = meta.block.header.height;
+        {
+            let conn = self.recv_pool.recv().await.map_err(StorageError::io)?;
+            let send_pool = self.send_pool.clone();
+            smol::unblock(move || {
+                let mut conn = scopeguard::guard(conn, |conn| send_pool.try_send(conn).unwrap());
+                let conn = conn.transaction()?;
+                let (format, block) = encode_block(&meta.block);
+                conn.execute(
+                    "insert into history (height, header, block, block_format) values ($1, $2, $3, $4)",
+                    params![height.0, meta.block.header.stdcode(), block, format],
+                )?;
+                conn.execute(
+                    "insert into consensus_proofs (height, proof) values ($1, $2)",
+                    params![height.0, meta.cproof.stdcode()],
+                )?;
+                index_transactions(&conn, &meta.block)?;
+                index_block_hash(&conn, &meta.block.header)?;
+                for (txhash, stake) in meta.stakes {
+                    conn.execute(
+                        "insert into stakes (txhash, height, stake_doc) values ($1, $2, $3) on conflict do nothing",
+                        params![txhash.to_string(), height.0, stake.stdcode()],
+                    )?;
+                }
+                // everything below the snapshot is simply absent, just like after pruning
+                conn.execute(
+                    "insert into misc (key, value) values ('pruned_below', $1) on conflict(key) do update set value = excluded.value",
+                    params![height.0],
+                )?;
+                conn.commit()?;
+                Ok::<_, StorageError>(())
+            })
+            .await?;
+        }
+        self.pruned_below.store(height.0, Ordering::SeqCst);
+        log::info!("imported state at height {}", height);
+
+        self.reload_tip().await?;
+        let next = self.highest_state().await?;
         self.mempool_mut().rebase(next);
         self.new_block_notify.notify(usize::MAX);
+        Ok(height)
+    }
 
-        Ok(())
+    /// Gets a shareable handle to the forest.
+    pub(super) fn forest_arc(&self) -> Arc<novasmt::Database<ForestStore>> {
+        self.forest.clone()
     }
 
     /// Gets the forest.
-    pub fn forest(&self) -> &novasmt::Database<MeshaCas> {
+    pub fn forest(&self) -> &novasmt::Database<ForestStore> {
         &self.forest
     }
 }
+
+/// Checks that a consensus proof carries enough votes for a header.
+pub(super) fn check_cproof(
+    stakes: &StakeSet,
+    header: &Header,
+    cproof: &ConsensusProof,
+) -> anyhow::Result<()> {
+    let mut total_votes = CoinValue(0);
+    let mut present_votes = CoinValue(0);
+    for stake_doc_bytes in stakes.pre_tip911().iter() {
+        let stake_doc: StakeDoc = stdcode::deserialize(&stake_doc_bytes.1)?;
+        if header.height.epoch() >= stake_doc.e_start
+            && header.height.epoch() < stake_doc.e_post_end
+        {
+            total_votes += stake_doc.syms_staked;
+            if let Some(v) = cproof.get(&stake_doc.pubkey) {
+                if stake_doc.pubkey.verify(&header.hash(), v) {
+                    present_votes += total_votes;
+                }
+            }
+        }
+    }
+    if present_votes.0 <= 2 * total_votes.0 / 3 {
+        anyhow::bail!(
+            "rejecting putative block {} due to insufficient votes ({}/{})",
+            header.height,
+            present_votes,
+            total_votes
+        )
+    }
+    Ok(())
+}
+
+/// The folder holding the database of a particular genesis.
+fn genesis_folder(mut db_folder: PathBuf, genesis: &GenesisConfig) -> PathBuf {
+    let genesis_id = tmelcrypt::hash_single(stdcode::serialize(genesis).unwrap());
+    db_folder.push(format!("{}/", hex::encode(genesis_id.0)));
+    db_folder
+}
+
+/// Locks a database folder until the returned file is closed.
+fn lock_folder(folder: &Path) -> anyhow::Result<std::fs::File> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(folder.join("LOCK"))
+        .context("cannot open lock file")?;
+    if let Err(err) = file.try_lock_exclusive() {
+        if err.kind() == fs2::lock_contended_error().kind() {
+            anyhow::bail!(
+                "database {} is already in use by another process",
+                folder.display()
+            );
+        }
+        return Err(err).context("cannot lock database");
+    }
+    Ok(file)
+}
+
+/// Works out which backend a database's forest is in, checking it against `wanted`.
+fn forest_backend(
+    conn: &rusqlite::Connection,
+    wanted: Option<ForestBackend>,
+) -> anyhow::Result<ForestBackend> {
+    let recorded: Option<String> = conn
+        .query_row(
+            "select value from misc where key = 'forest_backend'",
+            params![],
+            |r| r.get(0),
+        )
+        .optional()?;
+    let has_history: bool =
+        conn.query_row("select count(*) > 0 from history", params![], |r| r.get(0))?;
+    let actual = match recorded {
+        Some(recorded) => recorded.parse()?,
+        // databases from before there was a choice
+        None if has_history => ForestBackend::Meshanina,
+        None => wanted.unwrap_or_default(),
+    };
+    // an in-memory forest died with the process that had it, and rolling back to a state it isn't needed for would throw away the whole history
+    if actual == ForestBackend::Memory && has_history {
+        anyhow::bail!(
+            "database has history, but its SMT forest was only kept in memory; start over with an empty database"
+        );
+    }
+    if let Some(wanted) = wanted {
+        if wanted != actual {
+            anyhow::bail!(
+                "database keeps its SMT forest in {}, not {}; move it with `melnode-db migrate-forest`",
+                actual,
+                wanted
+            );
+        }
+    }
+    Ok(actual)
+}
+
+/// Checks whether the SMT roots of a header are all present in the store.
+fn roots_resolve(store: &ForestStore, header: &Header) -> bool {
+    [header.coins_hash, header.history_hash, header.pools_hash]
+        .iter()
+        .all(|root| root.0 == [0u8; 32] || store.get(&root.0).is_some())
+}
+
+/// Gets the stakeset at the start of an epoch, building and caching it as needed.
+fn build_epoch_stakes(
+    conn: &rusqlite::Connection,
+    genesis: &GenesisConfig,
+    cache: &Cache<u64, StakeSet>,
+    epoch: u64,
+    final_epoch: u64,
+    save: bool,
+) -> Result<StakeSet, StorageError> {
+    let mut start = epoch;
+    let mut stakes = loop {
+        if let Some(stakes) = cache.get(&start) {
+            break stakes;
+        }
+        let saved: Option<Vec<u8>> = conn
+            .query_row(
+                "select stakes from stakeset_epochs where epoch = $1",
+                params![start],
+                |r| r.get(0),
+            )
+            .optional()?;
+        if let Some(saved) = saved {
+            let saved: Vec<(TxHash, StakeDoc)> = decode(&saved)?;
+            let stakes = StakeSet::new(saved.into_iter());
+            cache.insert(start, stakes.clone());
+            break stakes;
+        }
+        if start == 0 {
+            break StakeSet::new(genesis.stakes.clone().into_iter());
+        }
+        start -= 1;
+    };
+    for next in start + 1..=epoch {
+        add_stake_rows(
+            &mut stakes,
+            conn,
+            (next - 1) * STAKE_EPOCH,
+            next * STAKE_EPOCH - 1,
+        )?;
+        stakes.unlock_old(next);
+        if next <= final_epoch {
+            if save {
+                let encoded: Vec<(TxHash, StakeDoc)> = stakes
+                    .iter()
+                    .map(|(txhash, stake)| (*txhash, *stake))
+                    .collect();
+                conn.execute(
+                    "insert into stakeset_epochs (epoch, stakes) values ($1, $2) on conflict do nothing",
+                    params![next, encoded.stdcode()],
+                )?;
+            }
+            cache.insert(next, stakes.clone());
+        }
+    }
+    Ok(stakes)
+}
+
+/// Adds the stakes created between two heights, inclusive.
+fn add_stake_rows(
+    stakes: &mut StakeSet,
+    conn: &rusqlite::Connection,
+    from: u64,
+    to: u64,
+) -> Result<(), StorageError> {
+    let mut stmt = conn.prepare_cached(
+        "select txhash, stake_doc from stakes where height >= $1 and height <= $2",
+    )?;
+    for row in stmt.query_map(params![from, to], |row| Ok((row.get(0)?, row.get(1)?)))? {
+        let row: (String, Vec<u8>) = row?;
+        let t: TxHash = row.0.parse().map_err(StorageError::corrupt)?;
+        let sd: StakeDoc = decode(&row.1)?;
+        stakes.add_stake(t, sd);
+    }
+    Ok(())
+}
+
+/// The stakes a block added, by diffing the stakesets before and after it.
+fn new_stakes(
+    prev: &SealedState<ForestStore>,
+    next: &SealedState<ForestStore>,
+) -> Vec<(TxHash, StakeDoc)> {
+    let prev = prev.raw_stakes();
+    next.raw_stakes()
+        .iter()
+        .filter(|(txhash, _)| prev.get_stake(**txhash).is_none())
+        .map(|(txhash, stake)| (*txhash, *stake))
+        .collect()
+}
+
+/// Saves an already-validated block, with its proof, stakes and index entries.
+fn insert_block(
+    conn: &rusqlite::Connection,
+    blk: &Block,
+    cproof: &ConsensusProof,
+    new_stakes: &[(TxHash, StakeDoc)],
+) -> rusqlite::Result<()> {
+    let (format, block) = encode_block(blk);
+    conn.execute(
+        "insert into history (height, header, block, block_format) values ($1, $2, $3, $4)",
+        params![blk.header.height.0, blk.header.stdcode(), block, format],
+    )?;
+    conn.execute(
+        "insert into consensus_proofs (height, proof) values ($1, $2)",
+        params![blk.header.height.0, stdcode::serialize(cproof).unwrap()],
+    )?;
+    index_transactions(conn, blk)?;
+    index_block_hash(conn, &blk.header)?;
+    for tx in blk.transactions.iter() {
+        conn.execute(
+            "delete from mempool_journal where txhash = $1",
+            params![tx.hash_nosigs().to_string()],
+        )?;
+    }
+    for (txhash, stake) in new_stakes {
+        conn.execute(
+            "insert into stakes (txhash, height, stake_doc) values ($1, $2, $3)",
+            params![txhash.to_string(), blk.header.height.0, stake.stdcode()],
+        )?;
+    }
+    Ok(())
+}
+
+/// The current Unix time, in seconds.
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The `received` time before which journaled transactions have expired, or `None` if journaling is off.
+///
+/// Tools open the node's database with journaling off, which mustn't wipe the node's journal.
+fn journal_cutoff(expiry: Duration) -> Option<u64> {
+    if expiry.is_zero() {
+        return None;
+    }
+    Some(unix_time().saturating_sub(expiry.as_secs()))
+}
+
+/// `history.block_format` of blocks stored as plain stdcode.
+pub(super) const BLOCK_FORMAT_RAW: u8 = 0;
+/// `history.block_format` of blocks stored as size-prefixed lz4.
+pub(super) const BLOCK_FORMAT_LZ4: u8 = 1;
+
+/// Encodes a block for `history`, returning its `block_format` too.
+pub(super) fn encode_block(blk: &Block) -> (u8, Vec<u8>) {
+    (BLOCK_FORMAT_LZ4, compress_block(&blk.stdcode()))
+}
+
+/// Compresses the stdcode encoding of a block into [BLOCK_FORMAT_LZ4].
+pub(super) fn compress_block(stdcode: &[u8]) -> Vec<u8> {
+    lz4_flex::compress_prepend_size(stdcode)
+}
+
+/// Recovers a block's stdcode from its `history` columns.
+pub(super) fn block_stdcode(format: u8, blob: Vec<u8>) -> Result<Vec<u8>, StorageError> {
+    match format {
+        BLOCK_FORMAT_RAW => Ok(blob),
+        BLOCK_FORMAT_LZ4 => {
+            lz4_flex::decompress_size_prepended(&blob).map_err(StorageError::corrupt)
+        }
+        other => Err(StorageError::Corrupt(format!(
+            "unknown block format {}",
+            other
+        ))),
+    }
+}
+
+/// A block's transactions sorted by hash, as the `transactions` table indexes them.
+fn sorted_transactions(block: &Block) -> Vec<Transaction> {
+    let mut txx: Vec<Transaction> = block.transactions.iter().cloned().collect();
+    txx.sort_unstable_by_key(|tx| tx.hash_nosigs());
+    txx
+}
+
+/// Records where each of a block's transactions is.
+pub(super) fn index_transactions(
+    conn: &rusqlite::Connection,
+    block: &Block,
+) -> rusqlite::Result<()> {
+    for (index, tx) in sorted_transactions(block).iter().enumerate() {
+        conn.execute(
+            "insert into transactions (txhash, height, idx) values ($1, $2, $3) on conflict(txhash) do update set height = excluded.height, idx = excluded.idx",
+            params![tx.hash_nosigs().to_string(), block.header.height.0, index as u32],
+        )?;
+    }
+    Ok(())
+}
+
+/// Records the hash of a block's header.
+pub(super) fn index_block_hash(
+    conn: &rusqlite::Connection,
+    header: &Header,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "insert into block_hashes (hash, height) values ($1, $2) on conflict(hash) do update set height = excluded.height",
+        params![header.hash().to_string(), header.height.0],
+    )?;
+    Ok(())
+}
@@ -0,0 +1,238 @@
+use std::collections::HashSet;
+
+use melstructs::{Block, BlockHeight, ConsensusProof, Header};
+use novasmt::Hashed;
+use serde::Serialize;
+use tmelcrypt::HashVal;
+
+use super::{block_stdcode, check_cproof, check_reachable_unseen, decode, Storage, StorageError};
+
+/// Most SMT node hashes that verification remembers, about 200 MB.
+const SEEN_LIMIT: usize = 1 << 22;
+
+/// A summary of an integrity check, produced by [Storage::verify].
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct VerifyReport {
+    /// Lowest height in the history table.
+    pub lowest: Option<BlockHeight>,
+    /// Highest height in the history table.
+    pub highest: Option<BlockHeight>,
+    /// How many heights were checked.
+    pub heights_checked: u64,
+    /// How many SMT nodes were checked.
+    pub smt_nodes_checked: u64,
+    /// Everything that was found wrong, in height order.
+    pub problems: Vec<VerifyProblem>,
+}
+
+/// One thing wrong with the database.
+#[derive(Clone, Debug, Serialize)]
+pub struct VerifyProblem {
+    pub height: BlockHeight,
+    pub kind: ProblemKind,
+    pub detail: String,
+}
+
+/// What kind of problem a [VerifyProblem] is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProblemKind {
+    /// A height, or its consensus proof, is absent.
+    Missing,
+    /// The database failed while reading a height.
+    Unreadable,
+    /// A stored row fails to decode, or disagrees with itself.
+    Corrupt,
+    /// A header's `previous` is not the hash of the header before it.
+    BrokenChain,
+    /// A consensus proof lacks enough votes.
+    BadConsensusProof,
+    /// The stakes table doesn't reproduce a header's `stakes_hash`.
+    StakesMismatch,
+    /// A header's SMT roots reach a node that isn't in the SMT forest.
+    MissingSmtNode,
+    /// A node in the SMT forest doesn't hash to its key, or doesn't fit its place in its tree.
+    CorruptSmtNode,
+}
+
+impl Storage {
+    /// Walks the whole database, checking for anything missing, corrupt or inconsistent.
+    pub async fn verify(&self) -> Result<VerifyReport, StorageError> {
+        let heights = self.stored_heights().await?;
+        let mut report = VerifyReport {
+            lowest: heights.first().copied(),
+            highest: heights.last().copied(),
+            ..Default::default()
+        };
+        let mut problem = |height, kind, detail: String| {
+            log::warn!("{:?} at height {}: {}", kind, height, detail);
+            report.problems.push(VerifyProblem {
+                height,
+                kind,
+                detail,
+            })
+        };
+
+        let mut seen: HashSet<Hashed> = HashSet::new();
+        let mut smt_nodes_checked = 0;
+        let mut prev_header: Option<Header> = None;
+        let mut prev_stakes = None;
+        let mut expected: Option<BlockHeight> = None;
+        for (i, height) in heights.iter().copied().enumerate() {
+            if i % 10000 == 0 {
+                log::info!("verifying height {} ({}/{})", height, i, heights.len());
+            }
+            if let Some(expected) = expected.filter(|e| *e < height) {
+                problem(
+                    expected,
+                    ProblemKind::Missing,
+                    format!("heights {}..={} are missing", expected, height.0 - 1),
+                );
+                prev_header = None;
+                prev_stakes = None;
+            }
+            expected = Some(height + BlockHeight(1));
+
+            let stakes = match self.get_stakeset(height).await {
+                Ok(stakes) => Some(stakes),
+                Err(err) => {
+                    problem(
+                        height,
+                        error_kind(&err),
+                        format!("cannot read stakes: {err}"),
+                    );
+                    None
+                }
+            };
+            // a failure to read the stakes below has already been reported
+            let vote_stakes = match prev_stakes.replace(stakes.clone()) {
+                Some(stakes) => stakes,
+                // the stakes just before a pruned or imported state aren't stored, but stakes added at the state itself can't vote on it anyway
+                None if height == self.pruned_below() => stakes.clone(),
+                None => match self.get_stakeset(BlockHeight(height.0 - 1)).await {
+                    Ok(stakes) => Some(stakes),
+                    Err(err) => {
+                        problem(
+                            height,
+                            error_kind(&err),
+                            format!("cannot read the stakes voting on it: {err}"),
+                        );
+                        None
+                    }
+                },
+            };
+            let parent = if height.0 == 1 {
+                Some(self.genesis_header())
+            } else {
+                prev_header.take()
+            };
+
+            let (header, format, block, proof) = match self.raw_row(height).await {
+                Ok(row) => row,
+                Err(err) => {
+                    problem(height, error_kind(&err), format!("cannot read row: {err}"));
+                    continue;
+                }
+            };
+            let header: Header = match stdcode::deserialize(&header) {
+                Ok(header) => header,
+                Err(err) => {
+                    problem(height, ProblemKind::Corrupt, format!("bad header: {err}"));
+                    continue;
+                }
+            };
+            prev_header = Some(header);
+            match block_stdcode(format, block).and_then(|block| decode::<Block>(&block)) {
+                Ok(block) if block.header != header => problem(
+                    height,
+                    ProblemKind::Corrupt,
+                    "block does not match the stored header".into(),
+                ),
+                Ok(_) => {}
+                Err(err) => problem(height, ProblemKind::Corrupt, format!("bad block: {err}")),
+            }
+            if let Some(parent) = parent {
+                if header.previous != parent.hash() {
+                    problem(
+                        height,
+                        ProblemKind::BrokenChain,
+                        format!(
+                            "previous is {}, but the header below hashes to {}",
+                            header.previous,
+                            parent.hash()
+                        ),
+                    );
+                }
+            }
+            if let Some(stakes) = &stakes {
+                if HashVal(stakes.pre_tip911().root_hash()) != header.stakes_hash {
+                    problem(
+                        height,
+                        ProblemKind::StakesMismatch,
+                        "stakes table does not reproduce stakes_hash".into(),
+                    );
+                }
+            }
+            match proof.map(|p| stdcode::deserialize::<ConsensusProof>(&p)) {
+                None => problem(height, ProblemKind::Missing, "no consensus proof".into()),
+                Some(Err(err)) => problem(
+                    height,
+                    ProblemKind::Corrupt,
+                    format!("bad consensus proof: {err}"),
+                ),
+                Some(Ok(proof)) => {
+                    if let Some(vote_stakes) = &vote_stakes {
+                        if let Err(err) = check_cproof(vote_stakes, &header, &proof) {
+                            problem(height, ProblemKind::BadConsensusProof, err.to_string());
+                        }
+                    }
+                }
+            }
+
+            // states share most of their nodes, so each node is only checked the first time it's reached, until there are too many to remember
+            if seen.len() > SEEN_LIMIT {
+                seen.clear();
+            }
+            let forest = self.forest_arc();
+            let (walked, corrupt, returned_seen) = smol::unblock(move || {
+                let mut corrupt = vec![];
+                let walked = check_reachable_unseen(
+                    forest.storage(),
+                    [
+                        header.coins_hash.0,
+                        header.history_hash.0,
+                        header.pools_hash.0,
+                    ],
+                    &mut seen,
+                    |hash, err| corrupt.push((hash, err)),
+                );
+                (walked, corrupt, seen)
+            })
+            .await;
+            seen = returned_seen;
+            match walked {
+                Ok(count) => smt_nodes_checked += count,
+                Err(err) => problem(height, ProblemKind::MissingSmtNode, err.to_string()),
+            }
+            for (hash, err) in corrupt {
+                problem(
+                    height,
+                    ProblemKind::CorruptSmtNode,
+                    format!("node {}: {:#}", hex::encode(hash), err),
+                );
+            }
+        }
+        report.heights_checked = heights.len() as u64;
+        report.smt_nodes_checked = smt_nodes_checked;
+        Ok(report)
+    }
+}
+
+/// The kind of problem that failing to read a height amounts to.
+fn error_kind(err: &StorageError) -> ProblemKind {
+    match err {
+        StorageError::NotFound(_) => ProblemKind::Missing,
+        StorageError::Corrupt(_) => ProblemKind::Corrupt,
+        _ => ProblemKind::Unreadable,
+    }
+}
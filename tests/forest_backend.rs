@@ -0,0 +1,75 @@
+//! Checks that a database whose in-memory forest is gone refuses to open, rather than rolling back to genesis.
+
+use std::collections::BTreeMap;
+
+use melnode::storage::{ForestBackend, Storage, StorageConfig};
+use melstf::GenesisConfig;
+use melstructs::{CoinData, CoinValue, ConsensusProof, Denom, NetID, StakeDoc, TxHash};
+use melvm::Covenant;
+use tmelcrypt::{Ed25519SK, HashVal};
+
+fn genesis(signer: &Ed25519SK) -> GenesisConfig {
+    let mut stakes = BTreeMap::new();
+    stakes.insert(
+        TxHash(HashVal::default()),
+        StakeDoc {
+            pubkey: signer.to_public(),
+            e_start: 0,
+            e_post_end: 1_000_000,
+            syms_staked: CoinValue(1_000_000),
+        },
+    );
+    GenesisConfig {
+        network: NetID::Custom08,
+        init_coindata: CoinData {
+            covhash: Covenant::always_true().hash(),
+            value: CoinValue(1_000_000),
+            denom: Denom::Mel,
+            additional_data: Default::default(),
+        },
+        stakes,
+        init_fee_pool: CoinValue(0),
+        init_fee_multiplier: 0,
+    }
+}
+
+#[test]
+fn memory_forest_with_history_is_refused() {
+    smolscale::block_on(async {
+        let dir =
+            std::env::temp_dir().join(format!("melnode-forest-backend-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let signer = Ed25519SK::generate();
+        let config = || StorageConfig {
+            forest_backend: Some(ForestBackend::Memory),
+            ..Default::default()
+        };
+        {
+            let storage = Storage::open(dir.clone(), genesis(&signer), config())
+                .await
+                .unwrap();
+            let block = storage
+                .highest_state()
+                .await
+                .unwrap()
+                .next_unsealed()
+                .seal(None)
+                .to_block();
+            let mut proof = ConsensusProof::new();
+            proof.insert(
+                signer.to_public(),
+                signer.sign(&block.header.hash().0).into(),
+            );
+            storage.apply_block(block, proof).await.unwrap();
+        }
+        let err = match Storage::open(dir.clone(), genesis(&signer), config()).await {
+            Ok(_) => panic!("reopened a database whose forest is gone"),
+            Err(err) => err,
+        };
+        assert!(
+            format!("{err:#}").contains("only kept in memory"),
+            "{err:#}"
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    });
+}
@@ -0,0 +1,118 @@
+//! Checks that opening a database with journaling off, as the database tools do, leaves the mempool journal alone.
+
+use std::{collections::BTreeMap, path::PathBuf, time::Duration};
+
+use melnode::storage::{ForestBackend, Storage, StorageConfig};
+use melstf::GenesisConfig;
+use melstructs::{
+    CoinData, CoinID, CoinValue, ConsensusProof, Denom, NetID, StakeDoc, Transaction, TxHash,
+    TxKind,
+};
+use melvm::Covenant;
+use tmelcrypt::{Ed25519SK, HashVal};
+
+struct TempDir(PathBuf);
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+fn genesis(signer: &Ed25519SK) -> GenesisConfig {
+    let mut stakes = BTreeMap::new();
+    // not keyed by the zero hash, which would lock the genesis coin as a stake
+    stakes.insert(
+        TxHash(HashVal([1; 32])),
+        StakeDoc {
+            pubkey: signer.to_public(),
+            e_start: 0,
+            e_post_end: 1_000_000,
+            syms_staked: CoinValue(1_000_000),
+        },
+    );
+    GenesisConfig {
+        network: NetID::Custom08,
+        init_coindata: CoinData {
+            covhash: Covenant::always_true().hash(),
+            value: CoinValue(1_000_000),
+            denom: Denom::Mel,
+            additional_data: Default::default(),
+        },
+        stakes,
+        init_fee_pool: CoinValue(0),
+        init_fee_multiplier: 0,
+    }
+}
+
+/// Spends the genesis coin back to the same covenant.
+fn spend() -> Transaction {
+    Transaction {
+        kind: TxKind::Normal,
+        inputs: vec![CoinID::zero_zero()],
+        outputs: vec![CoinData {
+            covhash: Covenant::always_true().hash(),
+            value: CoinValue(1_000_000),
+            denom: Denom::Mel,
+            additional_data: Default::default(),
+        }],
+        fee: CoinValue(0),
+        covenants: vec![Covenant::always_true().to_bytes()],
+        data: Default::default(),
+        sigs: vec![],
+    }
+}
+
+#[test]
+fn journal_survives_journaling_off() {
+    smolscale::block_on(async {
+        let dir = TempDir(
+            std::env::temp_dir().join(format!("melnode-mempool-journal-{}", std::process::id())),
+        );
+        let _ = std::fs::remove_dir_all(&dir.0);
+        let signer = Ed25519SK::generate();
+        // meshanina holds its lock for a while after being dropped, which gets in the way of reopening
+        let journaling = || StorageConfig {
+            forest_backend: Some(ForestBackend::Sqlite),
+            mempool_expiry: Duration::from_secs(3600),
+            ..Default::default()
+        };
+        let tx = spend();
+        {
+            let storage = Storage::open(dir.0.clone(), genesis(&signer), journaling())
+                .await
+                .unwrap();
+            storage.mempool_mut().apply_transaction(&tx).unwrap();
+            storage.journal_tx(&tx).await.unwrap();
+        }
+        // journal times are in whole seconds, and a zero expiry would delete everything received before now
+        std::thread::sleep(Duration::from_millis(1100));
+        {
+            // like a database tool: journaling off, and a block applied that doesn't include the tx
+            let storage = Storage::open(dir.0.clone(), genesis(&signer), Default::default())
+                .await
+                .unwrap();
+            assert!(storage.mempool().pending_tx(tx.hash_nosigs()).is_none());
+            let block = storage
+                .highest_state()
+                .await
+                .unwrap()
+                .next_unsealed()
+                .seal(None)
+                .to_block();
+            let mut proof = ConsensusProof::new();
+            proof.insert(
+                signer.to_public(),
+                signer.sign(&block.header.hash().0).into(),
+            );
+            storage.apply_block(block, proof).await.unwrap();
+        }
+        let storage = Storage::open(dir.0.clone(), genesis(&signer), journaling())
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.mempool().pending_tx(tx.hash_nosigs()),
+            Some(tx.clone())
+        );
+    });
+}
@@ -0,0 +1,43 @@
+//! Checks that a read-only forest reads back what meshanina writes, including what it appends later.
+
+use melnode::storage::ForestBackend;
+use novasmt::ContentAddrStore;
+
+fn key(i: u64) -> [u8; 32] {
+    novasmt::hash_data(&i.to_be_bytes())
+}
+
+fn value(i: u64) -> Vec<u8> {
+    i.to_string().repeat(i as usize % 50 + 1).into_bytes()
+}
+
+#[test]
+fn reads_what_meshanina_writes() {
+    let dir = std::env::temp_dir().join(format!("melnode-mesha-reader-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let writer = ForestBackend::Meshanina.open(&dir).unwrap();
+    for i in 0..1000 {
+        writer.insert(&key(i), &value(i));
+    }
+    writer.flush().unwrap();
+    let reader = ForestBackend::Meshanina.open_readonly(&dir).unwrap();
+    for i in 0..1000 {
+        assert_eq!(reader.get(&key(i)).unwrap().as_ref(), value(i));
+    }
+    assert!(reader.get(&key(1000)).is_none());
+
+    // the reader picks up whatever gets flushed after it was opened
+    for i in 1000..2000 {
+        writer.insert(&key(i), &value(i));
+    }
+    writer.flush().unwrap();
+    for i in 0..2000 {
+        assert_eq!(reader.get(&key(i)).unwrap().as_ref(), value(i));
+    }
+
+    drop(reader);
+    drop(writer);
+    let _ = std::fs::remove_dir_all(&dir);
+}
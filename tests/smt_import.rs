@@ -0,0 +1,86 @@
+//! Checks that state sync's SMT import rejects nodes that hash right but don't fit their trees.
+
+use std::path::Path;
+
+use melnode::storage::{ForestBackend, ForestStore, SmtImport};
+use novasmt::{ContentAddrStore, Database, Hashed};
+
+/// A tree big enough to have hexary nodes below its root.
+fn source() -> (Database<ForestStore>, Hashed) {
+    let db = Database::new(ForestBackend::Memory.open(Path::new(".")).unwrap());
+    let mut tree = db.get_tree(Hashed::default()).unwrap();
+    for i in 0u64..1000 {
+        tree.insert(novasmt::hash_data(&i.to_be_bytes()), &i.to_be_bytes());
+    }
+    let root = tree.root_hash();
+    (db, root)
+}
+
+/// Syncs a tree into a fresh store the way state sync does, passing every node through `tamper`.
+fn sync(tamper: fn(&mut Vec<u8>)) -> (anyhow::Result<()>, ForestStore, Hashed) {
+    let (source, root) = source();
+    let target = ForestBackend::Memory.open(Path::new(".")).unwrap();
+    let result = (|| {
+        let mut import = SmtImport::new(&target, [root]);
+        loop {
+            let wanted = import.next_wanted(100);
+            if wanted.is_empty() {
+                break;
+            }
+            for hash in wanted {
+                let mut node = source.storage().get(&hash).unwrap().to_vec();
+                tamper(&mut node);
+                import.insert(node)?;
+            }
+        }
+        import.finish()
+    })();
+    (result, target, root)
+}
+
+#[test]
+fn untampered_sync_is_complete() {
+    let (result, target, root) = sync(|_| {});
+    result.unwrap();
+    let tree = Database::new(target).get_tree(root).unwrap();
+    for i in 0u64..1000 {
+        assert_eq!(
+            tree.get(novasmt::hash_data(&i.to_be_bytes())).as_ref(),
+            &i.to_be_bytes()
+        );
+    }
+}
+
+#[test]
+fn flipped_count_is_rejected() {
+    let (result, target, root) = sync(|node| {
+        if node[0] == 64 {
+            node[1] ^= 1;
+        }
+    });
+    assert!(result.is_err());
+    assert!(target.get(&root).is_none());
+}
+
+#[test]
+fn flipped_height_is_rejected() {
+    let (result, target, root) = sync(|node| {
+        if node[0] == 63 {
+            node[0] = 62;
+        }
+    });
+    assert!(result.is_err());
+    assert!(target.get(&root).is_none());
+}
+
+#[test]
+fn key_off_its_path_is_rejected() {
+    // a single node's hash only covers the key bits below it
+    let (result, target, root) = sync(|node| {
+        if node[0] == 0 {
+            node[2] ^= 0x80;
+        }
+    });
+    assert!(result.is_err());
+    assert!(target.get(&root).is_none());
+}
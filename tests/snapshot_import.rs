@@ -0,0 +1,127 @@
+//! Checks that importing a snapshot rejects SMT nodes that hash right but don't fit their trees.
+
+use std::{collections::BTreeMap, path::PathBuf};
+
+use melnode::storage::{SnapshotReader, SnapshotWriter, Storage};
+use melstf::GenesisConfig;
+use melstructs::{CoinData, CoinValue, ConsensusProof, Denom, NetID, StakeDoc, TxHash};
+use melvm::Covenant;
+use tmelcrypt::{Ed25519SK, HashVal};
+
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "melnode-snapshot-import-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+fn genesis(signer: &Ed25519SK) -> GenesisConfig {
+    let mut stakes = BTreeMap::new();
+    stakes.insert(
+        TxHash(HashVal::default()),
+        StakeDoc {
+            pubkey: signer.to_public(),
+            e_start: 0,
+            e_post_end: 1_000_000,
+            syms_staked: CoinValue(1_000_000),
+        },
+    );
+    GenesisConfig {
+        network: NetID::Custom08,
+        init_coindata: CoinData {
+            covhash: Covenant::always_true().hash(),
+            value: CoinValue(1_000_000),
+            denom: Denom::Mel,
+            additional_data: Default::default(),
+        },
+        stakes,
+        init_fee_pool: CoinValue(0),
+        init_fee_multiplier: 0,
+    }
+}
+
+/// Rewrites a snapshot, passing every node through `tamper`.
+fn rewrite(from: &PathBuf, to: &PathBuf, tamper: impl Fn(HashVal, &mut Vec<u8>)) {
+    let (mut reader, meta) =
+        SnapshotReader::new(std::io::BufReader::new(std::fs::File::open(from).unwrap())).unwrap();
+    let mut writer = SnapshotWriter::new(std::fs::File::create(to).unwrap(), &meta).unwrap();
+    while let Some((hash, mut node)) = reader.next_node().unwrap() {
+        tamper(HashVal(hash), &mut node);
+        writer.write_node(hash, &node).unwrap();
+    }
+    writer.finish().unwrap();
+}
+
+async fn tampered_node_is_rejected(name: &str, tamper: fn(&mut Vec<u8>)) {
+    let dir = TempDir::new(name);
+    let signer = Ed25519SK::generate();
+    let source = Storage::open(dir.0.join("source"), genesis(&signer), Default::default())
+        .await
+        .unwrap();
+    for _ in 0..5 {
+        let block = source
+            .highest_state()
+            .await
+            .unwrap()
+            .next_unsealed()
+            .seal(None)
+            .to_block();
+        let mut proof = ConsensusProof::new();
+        proof.insert(
+            signer.to_public(),
+            signer.sign(&block.header.hash().0).into(),
+        );
+        source.apply_block(block, proof).await.unwrap();
+    }
+    let tip = source.highest_height().await.unwrap();
+    let header = source.get_block(tip).await.unwrap().header;
+    let good = dir.0.join("good.snapshot");
+    let bad = dir.0.join("bad.snapshot");
+    source.export_snapshot(tip, good.clone()).await.unwrap();
+
+    // the history tree holds every earlier header, so its root is a hexary node
+    rewrite(&good, &bad, |hash, node| {
+        if hash == header.history_hash {
+            tamper(node)
+        }
+    });
+    let target = Storage::open(dir.0.join("target"), genesis(&signer), Default::default())
+        .await
+        .unwrap();
+    let err = target
+        .import_snapshot(bad, header.hash())
+        .await
+        .unwrap_err();
+    assert!(format!("{err:#}").contains("bad SMT node"), "{err:#}");
+    assert_eq!(target.highest_height().await.unwrap().0, 0);
+
+    // the tampered node must not have stuck around in place of the real one
+    assert_eq!(
+        target.import_snapshot(good, header.hash()).await.unwrap(),
+        tip
+    );
+}
+
+#[test]
+fn tampered_count_is_rejected() {
+    smolscale::block_on(tampered_node_is_rejected("count", |node| node[1] ^= 1));
+}
+
+#[test]
+fn tampered_height_is_rejected() {
+    smolscale::block_on(tampered_node_is_rejected("height", |node| node[0] -= 1));
+}
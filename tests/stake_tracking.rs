@@ -0,0 +1,246 @@
+//! Checks that the stakes table tracks exactly the stakes the state transition accepts.
+
+use std::{collections::BTreeMap, path::PathBuf};
+
+use melnode::storage::{SnapshotMeta, Storage};
+use melstf::{CoinMapping, GenesisConfig, SmtMapping};
+use melstructs::{
+    Block, BlockHeight, CoinData, CoinDataHeight, CoinID, CoinValue, ConsensusProof, Denom, Header,
+    NetID, StakeDoc, Transaction, TxHash, TxKind,
+};
+use melvm::Covenant;
+use stdcode::StdcodeSerializeExt;
+use tmelcrypt::{Ed25519SK, HashVal};
+
+/// Height of the first replayed block.
+const START: u64 = 499_991;
+
+/// Stakes below this height don't count, under the old rules.
+const ACTIVATION: u64 = 500_000;
+
+struct Chain {
+    storage: Storage,
+    signer: Ed25519SK,
+    dir: PathBuf,
+}
+
+impl Chain {
+    /// Opens a fresh storage whose state jumps from genesis to just below [START].
+    async fn new(network: NetID) -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "melnode-stake-tracking-{:?}-{}",
+            network,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        let signer = Ed25519SK::generate();
+        let mut stakes = BTreeMap::new();
+        stakes.insert(
+            TxHash(HashVal::random()),
+            StakeDoc {
+                pubkey: signer.to_public(),
+                e_start: 0,
+                e_post_end: 1_000_000,
+                syms_staked: CoinValue(1_000_000),
+            },
+        );
+        let genesis = GenesisConfig {
+            network,
+            init_coindata: coin(Denom::Sym, 1_000_000),
+            stakes: stakes.clone(),
+            init_fee_pool: CoinValue(0),
+            init_fee_multiplier: 0,
+        };
+        let storage = Storage::open(dir.clone(), genesis, Default::default())
+            .await
+            .unwrap();
+
+        // the header below the jump has to be in the history SMT, so that `previous` resolves
+        let genesis_header = storage.highest_state().await.unwrap().header();
+        let mut history: SmtMapping<_, BlockHeight, Header> = SmtMapping::new(
+            storage
+                .forest()
+                .get_tree(genesis_header.history_hash.0)
+                .unwrap(),
+        );
+        history.insert(BlockHeight(START - 2), genesis_header);
+        // every transaction needs a mel input to balance its fee, even a zero one, so there's a mel coin next to the genesis syms
+        let mut coins = CoinMapping::new(storage.forest().get_tree(Default::default()).unwrap());
+        for (index, denom) in [Denom::Sym, Denom::Mel].into_iter().enumerate() {
+            coins.insert_coin(
+                CoinID::new(TxHash(HashVal::default()), index as u8),
+                CoinDataHeight {
+                    coin_data: coin(denom, 1_000_000),
+                    height: BlockHeight(0),
+                },
+                // only mainnet is still below the height where coin counts are committed to
+                network != NetID::Mainnet,
+            );
+        }
+        let header = Header {
+            previous: genesis_header.hash(),
+            height: BlockHeight(START - 1),
+            history_hash: history.root_hash(),
+            coins_hash: coins.root_hash(),
+            ..genesis_header
+        };
+        let chain = Self {
+            storage,
+            signer,
+            dir,
+        };
+        chain
+            .storage
+            .import_state(SnapshotMeta {
+                block: Block {
+                    header,
+                    transactions: Default::default(),
+                    proposer_action: None,
+                },
+                cproof: chain.sign(&header),
+                stakes: stakes.into_iter().collect(),
+            })
+            .await
+            .unwrap();
+        chain
+    }
+
+    fn sign(&self, header: &Header) -> ConsensusProof {
+        let mut proof = ConsensusProof::new();
+        proof.insert(
+            self.signer.to_public(),
+            self.signer.sign(&header.hash().0).into(),
+        );
+        proof
+    }
+
+    /// Seals transactions into the next block and applies it.
+    async fn advance(&self, txx: &[Transaction]) -> BlockHeight {
+        let mut next = self.storage.highest_state().await.unwrap().next_unsealed();
+        for tx in txx {
+            next.apply_tx(tx).unwrap();
+        }
+        let block = next.seal(None).to_block();
+        let proof = self.sign(&block.header);
+        self.storage
+            .apply_block(block.clone(), proof)
+            .await
+            .unwrap();
+        block.header.height
+    }
+
+    async fn advance_to(&self, height: u64) {
+        while self.storage.highest_height().await.unwrap().0 < height - 1 {
+            self.advance(&[]).await;
+        }
+    }
+}
+
+impl Drop for Chain {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn coin(denom: Denom, value: u128) -> CoinData {
+    CoinData {
+        covhash: Covenant::always_true().hash(),
+        value: CoinValue(value),
+        denom,
+        additional_data: Default::default(),
+    }
+}
+
+fn stake_tx(syms: CoinID, mels: CoinID, staker: &Ed25519SK) -> Transaction {
+    Transaction {
+        kind: TxKind::Stake,
+        inputs: vec![syms, mels],
+        outputs: vec![coin(Denom::Sym, 1000), coin(Denom::Mel, 1000)],
+        fee: CoinValue(0),
+        covenants: vec![Covenant::always_true().to_bytes()],
+        data: StakeDoc {
+            pubkey: staker.to_public(),
+            e_start: BlockHeight(ACTIVATION).epoch() + 1,
+            e_post_end: BlockHeight(ACTIVATION).epoch() + 2,
+            syms_staked: CoinValue(1000),
+        }
+        .stdcode()
+        .into(),
+        sigs: vec![],
+    }
+}
+
+async fn replay(network: NetID) {
+    let chain = Chain::new(network).await;
+    let split = Transaction {
+        kind: TxKind::Normal,
+        inputs: vec![
+            CoinID::zero_zero(),
+            CoinID::new(TxHash(HashVal::default()), 1),
+        ],
+        outputs: vec![
+            coin(Denom::Sym, 1000),
+            coin(Denom::Sym, 1000),
+            coin(Denom::Sym, 1000),
+            coin(Denom::Sym, 997_000),
+            coin(Denom::Mel, 1000),
+            coin(Denom::Mel, 1000),
+            coin(Denom::Mel, 1000),
+            coin(Denom::Mel, 997_000),
+        ],
+        fee: CoinValue(0),
+        covenants: vec![Covenant::always_true().to_bytes()],
+        data: Default::default(),
+        sigs: vec![],
+    };
+    assert_eq!(chain.advance(std::slice::from_ref(&split)).await.0, START);
+
+    let staker = Ed25519SK::generate();
+    let stakes: Vec<_> = (0..3)
+        .map(|i| {
+            stake_tx(
+                CoinID::new(split.hash_nosigs(), i),
+                CoinID::new(split.hash_nosigs(), i + 4),
+                &staker,
+            )
+        })
+        .collect();
+    // well before activation, exactly at the activation height, and after it
+    for (stake, height) in stakes
+        .iter()
+        .zip([ACTIVATION - 5, ACTIVATION, ACTIVATION + 2])
+    {
+        chain.advance_to(height).await;
+        assert_eq!(chain.advance(std::slice::from_ref(stake)).await.0, height);
+    }
+    let tip = chain.advance(&[]).await;
+
+    // every height's stakes must reproduce its header's stakes_hash, or get_state errors out
+    for height in START..=tip.0 {
+        chain.storage.get_state(BlockHeight(height)).await.unwrap();
+    }
+    let tracked = chain.storage.get_stakeset(tip).await.unwrap();
+    let expected = chain.storage.highest_state().await.unwrap().raw_stakes();
+    for stake in stakes.iter() {
+        assert_eq!(
+            tracked.get_stake(stake.hash_nosigs()).is_some(),
+            expected.get_stake(stake.hash_nosigs()).is_some()
+        );
+    }
+    assert!(tracked.get_stake(stakes[0].hash_nosigs()).is_none());
+    assert!(tracked.get_stake(stakes[1].hash_nosigs()).is_some());
+    assert!(tracked.get_stake(stakes[2].hash_nosigs()).is_some());
+
+    let report = chain.storage.verify().await.unwrap();
+    assert!(report.problems.is_empty(), "{:?}", report.problems);
+}
+
+#[test]
+fn mainnet_stakes_follow_the_state_transition() {
+    smolscale::block_on(replay(NetID::Mainnet));
+}
+
+#[test]
+fn testnet_stakes_follow_the_state_transition() {
+    smolscale::block_on(replay(NetID::Testnet));
+}
@@ -0,0 +1,103 @@
+//! Checks that verification catches SMT nodes that hash right but don't fit their trees.
+
+use std::{collections::BTreeMap, path::PathBuf};
+
+use melnode::storage::{ForestBackend, ProblemKind, Storage, StorageConfig};
+use melstf::GenesisConfig;
+use melstructs::{CoinData, CoinValue, ConsensusProof, Denom, NetID, StakeDoc, TxHash};
+use melvm::Covenant;
+use novasmt::ContentAddrStore;
+use tmelcrypt::{Ed25519SK, HashVal};
+
+struct TempDir(PathBuf);
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+fn genesis(signer: &Ed25519SK) -> GenesisConfig {
+    let mut stakes = BTreeMap::new();
+    stakes.insert(
+        TxHash(HashVal::default()),
+        StakeDoc {
+            pubkey: signer.to_public(),
+            e_start: 0,
+            e_post_end: 1_000_000,
+            syms_staked: CoinValue(1_000_000),
+        },
+    );
+    GenesisConfig {
+        network: NetID::Custom08,
+        init_coindata: CoinData {
+            covhash: Covenant::always_true().hash(),
+            value: CoinValue(1_000_000),
+            denom: Denom::Mel,
+            additional_data: Default::default(),
+        },
+        stakes,
+        init_fee_pool: CoinValue(0),
+        init_fee_multiplier: 0,
+    }
+}
+
+#[test]
+fn corrupt_count_is_found() {
+    smolscale::block_on(async {
+        let dir = TempDir(
+            std::env::temp_dir().join(format!("melnode-verify-count-{}", std::process::id())),
+        );
+        let _ = std::fs::remove_dir_all(&dir.0);
+        let signer = Ed25519SK::generate();
+        // an in-memory forest lets the node be overwritten in place
+        let storage = Storage::open(
+            dir.0.clone(),
+            genesis(&signer),
+            StorageConfig {
+                forest_backend: Some(ForestBackend::Memory),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        for _ in 0..5 {
+            let block = storage
+                .highest_state()
+                .await
+                .unwrap()
+                .next_unsealed()
+                .seal(None)
+                .to_block();
+            let mut proof = ConsensusProof::new();
+            proof.insert(
+                signer.to_public(),
+                signer.sign(&block.header.hash().0).into(),
+            );
+            storage.apply_block(block, proof).await.unwrap();
+        }
+        let report = storage.verify().await.unwrap();
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+
+        // the history tree holds every earlier header, so its root is a hexary node, whose hash doesn't cover its count
+        let root = storage
+            .highest_state()
+            .await
+            .unwrap()
+            .header()
+            .history_hash
+            .0;
+        let mut node = storage.forest().storage().get(&root).unwrap().to_vec();
+        node[1] ^= 1;
+        storage.forest().storage().insert(&root, &node);
+        let report = storage.verify().await.unwrap();
+        assert!(
+            report
+                .problems
+                .iter()
+                .any(|p| p.kind == ProblemKind::CorruptSmtNode),
+            "{:?}",
+            report.problems
+        );
+    });
+}